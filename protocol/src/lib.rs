@@ -0,0 +1,575 @@
+use std::error::Error;
+use std::fmt;
+
+use bytes::{Buf, BufMut, BytesMut};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+
+// Represent a transaction. `from` is the sender's base58 public key and
+// `signature` its base58 ed25519 signature over the other fields. Amounts
+// are whole units so balances can be checked exactly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Transaction {
+    pub from: String,
+    pub to: String,
+    #[serde(deserialize_with = "deserialize_amount")]
+    pub amount: u64,
+    pub timestamp: u64,
+    pub signature: String,
+}
+
+// Amounts were floats before protocol version 2; refuse those outright rather
+// than rounding them into something the sender didn't sign for
+fn deserialize_amount<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    struct AmountVisitor;
+
+    impl Visitor<'_> for AmountVisitor {
+        type Value = u64;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a whole number of units")
+        }
+
+        fn visit_u64<E: de::Error>(self, amount: u64) -> Result<u64, E> {
+            Ok(amount)
+        }
+
+        fn visit_i64<E: de::Error>(self, amount: i64) -> Result<u64, E> {
+            u64::try_from(amount).map_err(|_| E::custom(format!("negative amount {}", amount)))
+        }
+
+        fn visit_f64<E: de::Error>(self, amount: f64) -> Result<u64, E> {
+            Err(E::custom(format!(
+                "amount {} is a float; amounts are whole units since protocol version 2",
+                amount
+            )))
+        }
+    }
+
+    deserializer.deserialize_any(AmountVisitor)
+}
+
+// The fields covered by a transaction's signature
+#[derive(Serialize)]
+struct SignedFields<'a> {
+    from: &'a str,
+    to: &'a str,
+    amount: u64,
+    timestamp: u64,
+}
+
+impl Transaction {
+    pub fn new_signed(key: &SigningKey, to: String, amount: u64, timestamp: u64) -> Self {
+        let mut transaction = Transaction {
+            from: encode_pubkey(&key.verifying_key()),
+            to,
+            amount,
+            timestamp,
+            signature: String::new(),
+        };
+        let signature = key.sign(&transaction.signing_bytes());
+        transaction.signature = bs58::encode(signature.to_bytes()).into_string();
+        transaction
+    }
+
+    // Check the signature was made by the key in `from`
+    pub fn verify(&self) -> bool {
+        let Some(key) = decode_pubkey(&self.from) else {
+            return false;
+        };
+        let Ok(signature) = bs58::decode(&self.signature).into_vec() else {
+            return false;
+        };
+        let Ok(signature) = Signature::from_slice(&signature) else {
+            return false;
+        };
+        key.verify(&self.signing_bytes(), &signature).is_ok()
+    }
+
+    // Why a transaction can't be accepted whatever the balances, if it can't.
+    // A zero amount moves nothing and would cost every node storage for free,
+    // and funds sent to anything but a public key could never be spent.
+    pub fn check(&self) -> Result<(), &'static str> {
+        if self.amount == 0 {
+            return Err("the amount is zero");
+        }
+        if decode_pubkey(&self.to).is_none() {
+            return Err("the recipient is not a public key");
+        }
+        if !self.verify() {
+            return Err("the signature doesn't match the sender");
+        }
+        Ok(())
+    }
+
+    fn signing_bytes(&self) -> Vec<u8> {
+        let fields = SignedFields {
+            from: &self.from,
+            to: &self.to,
+            amount: self.amount,
+            timestamp: self.timestamp,
+        };
+        serde_json::to_vec(&fields).expect("signed fields always serialize")
+    }
+}
+
+pub fn encode_pubkey(key: &VerifyingKey) -> String {
+    bs58::encode(key.as_bytes()).into_string()
+}
+
+pub fn decode_pubkey(pubkey: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = bs58::decode(pubkey).into_vec().ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+// Version carried in Hello; nodes only talk to peers on the same version.
+// Version 2 changed transaction amounts from floats to whole units,
+// version 3 added request ids to queries, version 4 added GetStats,
+// version 5 added Resync, and version 6 made sync follow the order each node
+// applied transactions in.
+pub const PROTOCOL_VERSION: u32 = 6;
+
+// A connected peer as reported by a node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerInfo {
+    pub node_id: String,
+    // Address the peer can be dialed on
+    pub addr: String,
+    // Latest keepalive round trip, once one has completed
+    #[serde(default)]
+    pub rtt_micros: Option<u64>,
+}
+
+// Traffic counted since a node started, or since a peer connected
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TrafficStats {
+    pub frames_in: u64,
+    pub frames_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // Frames that didn't hold a valid message
+    pub invalid_frames: u64,
+    // Transactions dropped for a bad signature or an overdraft
+    pub rejected_transactions: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerStats {
+    pub node_id: String,
+    pub traffic: TrafficStats,
+}
+
+// Time from receiving a new transaction to having written it to every peer
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FanoutStats {
+    pub relays: u64,
+    pub mean_micros: u64,
+    pub max_micros: u64,
+}
+
+// Answer to GetStats
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeStats {
+    pub active_connections: u64,
+    pub total: TrafficStats,
+    pub peers: Vec<PeerStats>,
+    pub fanout: FanoutStats,
+}
+
+// Messages exchanged between nodes, one per frame
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    // Mandatory first frame in both directions. Clients that don't accept
+    // connections, like the CLI, advertise listen_port 0.
+    Hello {
+        node_id: String,
+        protocol_version: u32,
+        listen_port: u16,
+    },
+    // Sent instead of carrying on when a connection is refused
+    Reject {
+        reason: String,
+    },
+    // Sent instead of a Hello by a node that is at its connection limits
+    Busy {
+        reason: String,
+    },
+    // `hops` counts the relays the transaction has been through; senders
+    // outside the network leave it at 0. It sits outside the signed fields.
+    Transaction {
+        #[serde(flatten)]
+        transaction: Transaction,
+        #[serde(default)]
+        hops: u8,
+    },
+    // Keepalive probe, answered with a Pong carrying the same nonce
+    Ping {
+        nonce: u64,
+    },
+    Pong {
+        nonce: u64,
+    },
+    // Ask a peer for the transactions it has applied, in the order it first
+    // applied them, starting after the one with signature `after`, or from
+    // the first if that is missing or never applied there
+    SyncRequest {
+        #[serde(default)]
+        after: Option<String>,
+    },
+    // One page of at most MAX_SYNC_BATCH transactions, encoding to at most
+    // MAX_SYNC_PAGE_LEN bytes; `more` asks the receiver to request the next page
+    SyncResponse {
+        transactions: Vec<Transaction>,
+        more: bool,
+    },
+    // Sent by a node that dropped `skipped` relays for a peer that fell too
+    // far behind, asking it to sync again to pick up what it missed
+    Resync {
+        skipped: u64,
+    },
+    // Queries carry a request id chosen by the asker, which the node copies
+    // into its answer so replies can be matched up among relayed traffic
+    GetStatus {
+        request_id: u64,
+    },
+    Status {
+        request_id: u64,
+        transaction_count: usize,
+        // Relayed transactions dropped because a connection fell too far behind
+        #[serde(default)]
+        dropped_relays: u64,
+    },
+    GetPeers {
+        request_id: u64,
+    },
+    Peers {
+        request_id: u64,
+        peers: Vec<PeerInfo>,
+    },
+    // Look up a transaction by its signature
+    GetTx {
+        request_id: u64,
+        id: String,
+    },
+    Tx {
+        request_id: u64,
+        transaction: Transaction,
+    },
+    GetBalance {
+        request_id: u64,
+        account: String,
+    },
+    Balance {
+        request_id: u64,
+        account: String,
+        balance: u64,
+    },
+    GetStats {
+        request_id: u64,
+    },
+    Stats {
+        request_id: u64,
+        stats: NodeStats,
+    },
+    // Answer to GetTx or GetBalance for a transaction or account the node doesn't know
+    NotFound {
+        request_id: u64,
+    },
+}
+
+impl Message {
+    // The request id of an answer to a query, or None for any other message
+    pub fn response_id(&self) -> Option<u64> {
+        match self {
+            Message::Status { request_id, .. }
+            | Message::Peers { request_id, .. }
+            | Message::Tx { request_id, .. }
+            | Message::Balance { request_id, .. }
+            | Message::Stats { request_id, .. }
+            | Message::NotFound { request_id } => Some(*request_id),
+            _ => None,
+        }
+    }
+}
+
+// Most transactions sent in one SyncResponse
+pub const MAX_SYNC_BATCH: usize = 128;
+
+// Largest frame body a node will accept or send
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+// Most bytes of encoded transactions in one SyncResponse, leaving room in
+// MAX_FRAME_LEN for the rest of the message
+pub const MAX_SYNC_PAGE_LEN: usize = MAX_FRAME_LEN - 1024;
+
+const LEN_PREFIX: usize = 4;
+
+#[derive(Debug)]
+pub enum CodecError {
+    // The length prefix announced a body larger than the codec allows
+    FrameTooLarge { len: usize, max: usize },
+    // The frame was complete but did not hold a valid message
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::FrameTooLarge { len, max } => {
+                write!(f, "frame of {} bytes exceeds limit of {} bytes", len, max)
+            }
+            CodecError::Json(e) => write!(f, "malformed message: {}", e),
+        }
+    }
+}
+
+impl Error for CodecError {}
+
+// Frames are a 4-byte big-endian length followed by that many bytes of JSON
+#[derive(Debug, Clone, Copy)]
+pub struct FrameCodec {
+    max_frame_len: usize,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        FrameCodec::new(MAX_FRAME_LEN)
+    }
+}
+
+impl FrameCodec {
+    pub fn new(max_frame_len: usize) -> Self {
+        FrameCodec { max_frame_len }
+    }
+
+    pub fn encode(&self, message: &Message, dst: &mut BytesMut) -> Result<(), CodecError> {
+        let body = serde_json::to_vec(message).map_err(CodecError::Json)?;
+        if body.len() > self.max_frame_len {
+            return Err(CodecError::FrameTooLarge {
+                len: body.len(),
+                max: self.max_frame_len,
+            });
+        }
+
+        dst.reserve(LEN_PREFIX + body.len());
+        dst.put_u32(body.len() as u32);
+        dst.extend_from_slice(&body);
+        Ok(())
+    }
+
+    // Returns Ok(None) until a whole frame is buffered. A malformed body is consumed
+    // so the stream stays in sync; an oversized prefix is not, and should end the connection.
+    pub fn decode(&self, src: &mut BytesMut) -> Result<Option<Message>, CodecError> {
+        if src.len() < LEN_PREFIX {
+            return Ok(None);
+        }
+
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if len > self.max_frame_len {
+            return Err(CodecError::FrameTooLarge {
+                len,
+                max: self.max_frame_len,
+            });
+        }
+        if src.len() < LEN_PREFIX + len {
+            src.reserve(LEN_PREFIX + len - src.len());
+            return Ok(None);
+        }
+
+        src.advance(LEN_PREFIX);
+        let body = src.split_to(len);
+        serde_json::from_slice(&body)
+            .map(Some)
+            .map_err(CodecError::Json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transaction() -> Message {
+        Message::Transaction {
+            transaction: Transaction {
+                from: "node1".to_string(),
+                to: "node2".to_string(),
+                amount: 100,
+                timestamp: 1234567890,
+                signature: String::new(),
+            },
+            hops: 2,
+        }
+    }
+
+    fn signed() -> Transaction {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        Transaction::new_signed(&key, "recipient".to_string(), 5, 1234567890)
+    }
+
+    #[test]
+    fn verifies_signed_transactions() {
+        let transaction = signed();
+        let key = SigningKey::from_bytes(&[7; 32]).verifying_key();
+        assert_eq!(decode_pubkey(&transaction.from), Some(key));
+        assert!(transaction.verify());
+    }
+
+    #[test]
+    fn rejects_tampered_transactions() {
+        let mut transaction = signed();
+        transaction.amount = 500;
+        assert!(!transaction.verify());
+
+        let mut transaction = signed();
+        transaction.from = encode_pubkey(&SigningKey::from_bytes(&[8; 32]).verifying_key());
+        assert!(!transaction.verify());
+
+        let mut transaction = signed();
+        transaction.signature = "not a signature".to_string();
+        assert!(!transaction.verify());
+    }
+
+    #[test]
+    fn checks_amounts_and_recipients() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let recipient = encode_pubkey(&SigningKey::from_bytes(&[8; 32]).verifying_key());
+        assert_eq!(
+            Transaction::new_signed(&key, recipient.clone(), 5, 1).check(),
+            Ok(())
+        );
+        assert!(
+            Transaction::new_signed(&key, recipient, 0, 1)
+                .check()
+                .is_err()
+        );
+        assert!(
+            Transaction::new_signed(&key, "x".repeat(1000), 5, 1)
+                .check()
+                .is_err()
+        );
+        assert!(signed().check().is_err());
+    }
+
+    fn encoded(message: &Message) -> BytesMut {
+        let mut buf = BytesMut::new();
+        FrameCodec::default().encode(message, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn round_trips_a_frame() {
+        let mut buf = encoded(&transaction());
+        assert_eq!(
+            FrameCodec::default().decode(&mut buf).unwrap(),
+            Some(transaction())
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn waits_for_partial_frames() {
+        let codec = FrameCodec::default();
+        let frame = encoded(&transaction());
+        let mut buf = BytesMut::new();
+
+        // Feed the frame one byte at a time, including a split length prefix
+        for (i, byte) in frame.iter().enumerate() {
+            assert_eq!(
+                codec.decode(&mut buf).unwrap(),
+                None,
+                "decoded early at byte {}",
+                i
+            );
+            buf.put_u8(*byte);
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(transaction()));
+    }
+
+    #[test]
+    fn decodes_back_to_back_frames() {
+        let codec = FrameCodec::default();
+        let mut buf = encoded(&transaction());
+        buf.extend_from_slice(&encoded(&transaction()));
+
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(transaction()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(transaction()));
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_oversized_frames_from_the_prefix() {
+        let codec = FrameCodec::new(16);
+        let mut buf = BytesMut::new();
+        buf.put_u32(17);
+
+        match codec.decode(&mut buf) {
+            Err(CodecError::FrameTooLarge { len: 17, max: 16 }) => {}
+            other => panic!("expected FrameTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_to_encode_oversized_frames() {
+        let mut buf = BytesMut::new();
+        let result = FrameCodec::new(16).encode(&transaction(), &mut buf);
+        assert!(matches!(result, Err(CodecError::FrameTooLarge { .. })));
+        assert!(buf.is_empty());
+    }
+
+    fn frame(body: &str) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u32(body.len() as u32);
+        buf.extend_from_slice(body.as_bytes());
+        buf
+    }
+
+    #[test]
+    fn transactions_without_a_hop_count_start_at_zero() {
+        let body =
+            r#"{"type":"transaction","from":"a","to":"b","amount":5,"timestamp":1,"signature":""}"#;
+        assert!(matches!(
+            FrameCodec::default().decode(&mut frame(body)),
+            Ok(Some(Message::Transaction { hops: 0, .. }))
+        ));
+    }
+
+    #[test]
+    fn rejects_float_amounts_from_older_versions() {
+        for amount in ["5.5", "5.0"] {
+            let body = format!(
+                r#"{{"type":"transaction","from":"a","to":"b","amount":{},"timestamp":1,"signature":""}}"#,
+                amount
+            );
+            match FrameCodec::default().decode(&mut frame(&body)) {
+                Err(CodecError::Json(e)) => {
+                    assert!(
+                        e.to_string().contains("whole units"),
+                        "unclear error: {}",
+                        e
+                    )
+                }
+                other => panic!("expected a Json error, got {:?}", other),
+            }
+        }
+
+        let body = r#"{"type":"transaction","from":"a","to":"b","amount":-5,"timestamp":1,"signature":""}"#;
+        assert!(matches!(
+            FrameCodec::default().decode(&mut frame(body)),
+            Err(CodecError::Json(_))
+        ));
+    }
+
+    #[test]
+    fn skips_malformed_bodies() {
+        let codec = FrameCodec::default();
+        let mut buf = BytesMut::new();
+        buf.put_u32(8);
+        buf.extend_from_slice(b"not json");
+        buf.extend_from_slice(&encoded(&transaction()));
+
+        assert!(matches!(codec.decode(&mut buf), Err(CodecError::Json(_))));
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(transaction()));
+    }
+}
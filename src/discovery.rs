@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+
+use mdns_sd::{ResolvedService, ServiceDaemon, ServiceEvent, ServiceInfo};
+use tokio::task::JoinHandle;
+
+use crate::BoxError;
+use crate::net::Dialer;
+
+// mDNS service type every node advertises itself under
+pub const SERVICE_TYPE: &str = "_p2psim._tcp.local.";
+
+// TXT property holding the advertising node's id
+const NODE_ID_PROPERTY: &str = "node_id";
+
+// Advertise the node listening on `listen` over mDNS, unless it only listens
+// on loopback, and dial every other node found the same way. Of each pair of
+// nodes that find each other, only the one with the lower id dials, so they
+// don't race to connect twice.
+pub fn start(dialer: Dialer, listen: SocketAddr) -> Result<(), BoxError> {
+    let daemon = ServiceDaemon::new()?;
+    match advertisement(dialer.node_id(), listen)? {
+        Some(info) => {
+            daemon.register(info)?;
+            println!("Advertising over mDNS as {}", SERVICE_TYPE);
+        }
+        None => println!(
+            "Warning: not advertising over mDNS, as other nodes can't reach {}; \
+             listen on 0.0.0.0 or [::] to be discovered",
+            listen.ip()
+        ),
+    }
+    let events = daemon.browse(SERVICE_TYPE)?;
+
+    tokio::spawn(async move {
+        // Dropping the daemon would stop the advertisement
+        let _daemon = daemon;
+        let mut dialing = HashMap::new();
+        while let Ok(event) = events.recv_async().await {
+            if let ServiceEvent::ServiceResolved(service) = event {
+                discovered(&dialer, &service, listen.ip(), &mut dialing).await;
+            }
+        }
+    });
+    Ok(())
+}
+
+// The service to advertise for a node listening on `listen`, or None if it
+// only listens on loopback, which other nodes would be handed but can't dial
+fn advertisement(node_id: &str, listen: SocketAddr) -> Result<Option<ServiceInfo>, BoxError> {
+    if listen.ip().is_loopback() {
+        return Ok(None);
+    }
+    let host = format!("{}.local.", node_id);
+    let properties = [(NODE_ID_PROPERTY, node_id)];
+    let info = if listen.ip().is_unspecified() {
+        // Listening everywhere: advertise whatever addresses the host has
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            node_id,
+            &host,
+            (),
+            listen.port(),
+            &properties[..],
+        )?
+        .enable_addr_auto()
+    } else {
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            node_id,
+            &host,
+            listen.ip(),
+            listen.port(),
+            &properties[..],
+        )?
+    };
+    Ok(Some(info))
+}
+
+async fn discovered(
+    dialer: &Dialer,
+    service: &ResolvedService,
+    local: IpAddr,
+    dialing: &mut HashMap<String, JoinHandle<()>>,
+) {
+    let Some(node_id) = service.get_property_val_str(NODE_ID_PROPERTY) else {
+        return;
+    };
+    if node_id <= dialer.node_id() {
+        return;
+    }
+    if dialing.get(node_id).is_some_and(|task| !task.is_finished()) {
+        return;
+    }
+    if !dialer.wants_peer(node_id).await {
+        return;
+    }
+    let addresses = service.addresses.iter().map(|scoped| scoped.to_ip_addr());
+    let Some(ip) = pick_address(addresses, local) else {
+        return;
+    };
+
+    let addr = SocketAddr::new(ip, service.port).to_string();
+    println!("Discovered node {} at {}", node_id, addr);
+    dialing.insert(node_id.to_string(), dialer.connect(addr));
+}
+
+// An advertised address in the same family as the one we listen on, since
+// those are the ones we can be sure to reach
+fn pick_address(addresses: impl Iterator<Item = IpAddr>, local: IpAddr) -> Option<IpAddr> {
+    let mut addresses: Vec<IpAddr> = addresses.collect();
+    addresses.sort();
+    addresses
+        .iter()
+        .find(|ip| ip.is_ipv4() == local.is_ipv4())
+        .or(addresses.first())
+        .copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advertises_only_reachable_addresses() {
+        let loopback = ["127.0.0.1:8000", "[::1]:8000"];
+        for listen in loopback {
+            let info = advertisement("node", listen.parse().unwrap()).unwrap();
+            assert!(info.is_none(), "advertised {}", listen);
+        }
+
+        let info = advertisement("node", "192.168.1.5:8000".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.get_port(), 8000);
+        assert_eq!(info.get_property_val_str(NODE_ID_PROPERTY), Some("node"));
+        let addresses: Vec<_> = info.get_addresses().iter().copied().collect();
+        assert_eq!(addresses, ["192.168.1.5".parse::<IpAddr>().unwrap()]);
+
+        let info = advertisement("node", "0.0.0.0:8000".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert!(info.is_addr_auto());
+        assert!(info.get_addresses().is_empty());
+    }
+
+    #[test]
+    fn prefers_addresses_in_our_family() {
+        let v4: IpAddr = "192.168.1.5".parse().unwrap();
+        let v6: IpAddr = "fd00::5".parse().unwrap();
+        let local_v6: IpAddr = "::".parse().unwrap();
+
+        assert_eq!(
+            pick_address([v6, v4].into_iter(), "0.0.0.0".parse().unwrap()),
+            Some(v4)
+        );
+        assert_eq!(pick_address([v4, v6].into_iter(), local_v6), Some(v6));
+        assert_eq!(pick_address([v4].into_iter(), local_v6), Some(v4));
+        assert_eq!(pick_address(std::iter::empty(), local_v6), None);
+    }
+}
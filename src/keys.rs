@@ -0,0 +1,48 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use ed25519_dalek::SigningKey;
+
+use crate::BoxError;
+
+pub fn generate() -> Result<SigningKey, getrandom::Error> {
+    let mut seed = [0u8; 32];
+    getrandom::fill(&mut seed)?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+// Random identifier a node introduces itself with in Hello
+pub fn generate_node_id() -> Result<String, getrandom::Error> {
+    let mut bytes = [0u8; 16];
+    getrandom::fill(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// Keypair files use the Solana CLI layout: a JSON array of the 32 secret
+// bytes followed by the 32 public key bytes
+pub fn read_keypair_file(path: &Path) -> Result<SigningKey, BoxError> {
+    let bytes: Vec<u8> = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let bytes: [u8; 64] = bytes
+        .try_into()
+        .map_err(|_| format!("{} does not hold a 64-byte keypair", path.display()))?;
+    Ok(SigningKey::from_keypair_bytes(&bytes)?)
+}
+
+pub fn write_keypair_file(path: &Path, key: &SigningKey) -> Result<(), BoxError> {
+    let json = serde_json::to_string(&key.to_keypair_bytes().to_vec())?;
+    write_private(path, json.as_bytes())?;
+    Ok(())
+}
+
+// Create a file readable only by its owner, refusing to overwrite an existing one
+pub(crate) fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::protocol::Transaction;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LedgerError {
+    // The sender's balance doesn't cover the amount
+    InsufficientFunds {
+        account: String,
+        balance: u64,
+        amount: u64,
+    },
+    // Crediting the recipient would overflow their balance
+    Overflow {
+        account: String,
+    },
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LedgerError::InsufficientFunds {
+                account,
+                balance,
+                amount,
+            } => write!(
+                f,
+                "{} has {} but tried to send {}",
+                account, balance, amount
+            ),
+            LedgerError::Overflow { account } => write!(f, "balance of {} would overflow", account),
+        }
+    }
+}
+
+impl Error for LedgerError {}
+
+// Account balances in integer units, keyed by base58 public key
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Ledger {
+    balances: HashMap<String, u64>,
+}
+
+impl Ledger {
+    // Start from a genesis allocation; repeated accounts are summed
+    pub fn new(genesis: impl IntoIterator<Item = (String, u64)>) -> Self {
+        let mut balances = HashMap::new();
+        for (account, amount) in genesis {
+            let balance: &mut u64 = balances.entry(account).or_default();
+            *balance = balance.saturating_add(amount);
+        }
+        Ledger { balances }
+    }
+
+    pub fn balance(&self, account: &str) -> u64 {
+        self.get(account).unwrap_or(0)
+    }
+
+    // The balance of an account, or None if it has never held funds
+    pub fn get(&self, account: &str) -> Option<u64> {
+        self.balances.get(account).copied()
+    }
+
+    // Debit `from` and credit `to`, changing nothing if either would fail.
+    // Returns whether `to` was new to the ledger, which `undo` needs to know.
+    pub fn apply(&mut self, transaction: &Transaction) -> Result<bool, LedgerError> {
+        if transaction.amount == 0 {
+            return Ok(false);
+        }
+        let balance = self.balance(&transaction.from);
+        if balance < transaction.amount {
+            return Err(LedgerError::InsufficientFunds {
+                account: transaction.from.clone(),
+                balance,
+                amount: transaction.amount,
+            });
+        }
+        if transaction.from != transaction.to
+            && self
+                .balance(&transaction.to)
+                .checked_add(transaction.amount)
+                .is_none()
+        {
+            return Err(LedgerError::Overflow {
+                account: transaction.to.clone(),
+            });
+        }
+
+        self.balances
+            .insert(transaction.from.clone(), balance - transaction.amount);
+        let created = !self.balances.contains_key(&transaction.to);
+        *self.balances.entry(transaction.to.clone()).or_default() += transaction.amount;
+        Ok(created)
+    }
+
+    // Take back the most recently applied transaction, given what `apply`
+    // returned for it, leaving the ledger exactly as it was before
+    pub fn undo(&mut self, transaction: &Transaction, created: bool) {
+        if transaction.amount == 0 {
+            return;
+        }
+        if created {
+            self.balances.remove(&transaction.to);
+        } else if let Some(balance) = self.balances.get_mut(&transaction.to) {
+            *balance -= transaction.amount;
+        }
+        *self.balances.entry(transaction.from.clone()).or_default() += transaction.amount;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(from: &str, to: &str, amount: u64) -> Transaction {
+        Transaction {
+            from: from.to_string(),
+            to: to.to_string(),
+            amount,
+            timestamp: 1,
+            signature: String::new(),
+        }
+    }
+
+    fn funded() -> Ledger {
+        Ledger::new([("alice".to_string(), 100)])
+    }
+
+    #[test]
+    fn moves_funds_between_accounts() {
+        let mut ledger = funded();
+        ledger.apply(&transfer("alice", "bob", 30)).unwrap();
+        assert_eq!(ledger.balance("alice"), 70);
+        assert_eq!(ledger.balance("bob"), 30);
+
+        ledger.apply(&transfer("alice", "alice", 70)).unwrap();
+        assert_eq!(ledger.balance("alice"), 70);
+    }
+
+    #[test]
+    fn rejects_overdrafts_without_changing_balances() {
+        let mut ledger = funded();
+        assert_eq!(
+            ledger.apply(&transfer("alice", "bob", 101)),
+            Err(LedgerError::InsufficientFunds {
+                account: "alice".to_string(),
+                balance: 100,
+                amount: 101,
+            })
+        );
+        assert!(ledger.apply(&transfer("bob", "alice", 1)).is_err());
+        assert_eq!(ledger, funded());
+    }
+
+    #[test]
+    fn undoes_applied_transactions() {
+        let mut ledger = funded();
+        let transfers = [
+            transfer("alice", "bob", 30),
+            transfer("bob", "carol", 10),
+            transfer("alice", "alice", 20),
+        ];
+        let created: Vec<bool> = transfers
+            .iter()
+            .map(|transaction| ledger.apply(transaction).unwrap())
+            .collect();
+        assert_eq!(created, [true, true, false]);
+        for (transaction, created) in transfers.iter().zip(created).rev() {
+            ledger.undo(transaction, created);
+        }
+        assert_eq!(ledger, funded());
+        assert_eq!(ledger.get("bob"), None);
+    }
+
+    #[test]
+    fn rejects_credits_that_would_overflow() {
+        let mut ledger = Ledger::new([("alice".to_string(), 1), ("bob".to_string(), u64::MAX)]);
+        assert!(matches!(
+            ledger.apply(&transfer("alice", "bob", 1)),
+            Err(LedgerError::Overflow { .. })
+        ));
+        assert_eq!(ledger.balance("alice"), 1);
+    }
+}
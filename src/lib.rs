@@ -0,0 +1,267 @@
+pub mod discovery;
+pub mod keys;
+pub mod ledger;
+pub mod metrics;
+pub mod net;
+pub mod state;
+pub mod tls;
+pub mod topology;
+pub mod wal;
+
+pub use p2p_protocol as protocol;
+
+use std::error::Error;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use ledger::Ledger;
+use net::{Client, DEFAULT_HANDSHAKE_TIMEOUT, Node, NodeOptions};
+use protocol::{Message, NodeStats, PeerInfo, Transaction};
+use tls::{TlsContext, TlsOptions};
+use tokio::net::TcpListener;
+use topology::Topology;
+
+// Error type shared across the crate
+pub type BoxError = Box<dyn Error + Send + Sync>;
+
+// Everything a node needs to start, as parsed from the command line
+pub struct NodeConfig {
+    // Address to accept connections on, IPv4 or IPv6
+    pub listen: SocketAddr,
+    pub peers: Vec<String>,
+    pub max_peers: usize,
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub keepalive: Duration,
+    pub max_hops: u8,
+    pub relay_queue_len: usize,
+    // Starting balance of each account, by base58 public key
+    pub genesis: Vec<(String, u64)>,
+    pub tls: Option<TlsOptions>,
+    // Where the node id and transaction log are kept between runs; without it
+    // the node starts empty with a fresh id each time
+    pub data_dir: Option<PathBuf>,
+    // Rewrite the data dir's write-ahead log from the replayed state on startup
+    pub compact: bool,
+    // Serve Prometheus metrics over HTTP on this port of the listen address
+    pub metrics_port: Option<u16>,
+    // Advertise the node and find peers over mDNS
+    pub mdns: bool,
+}
+
+pub async fn run(config: NodeConfig) -> Result<(), BoxError> {
+    let tls = config
+        .tls
+        .as_ref()
+        .map(|options| {
+            let name = format!("p2p-node-{}", config.listen.port());
+            TlsContext::for_node(options, &name, config.listen.ip())
+        })
+        .transpose()?;
+    let secured = if tls.is_some() { " (TLS)" } else { "" };
+    let node_id = match &config.data_dir {
+        Some(dir) => load_node_id(dir)?,
+        None => keys::generate_node_id()?,
+    };
+    let options = NodeOptions {
+        node_id,
+        tls,
+        max_peers: config.max_peers,
+        max_connections: config.max_connections,
+        max_connections_per_ip: config.max_connections_per_ip,
+        handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        keepalive: config.keepalive,
+        max_hops: config.max_hops,
+        relay_queue_len: config.relay_queue_len,
+        genesis: Ledger::new(config.genesis),
+        wal: config.data_dir.as_ref().map(|dir| dir.join("wal")),
+        compact_wal: config.compact,
+    };
+
+    // Listen for incoming connections
+    let node = Node::bind(config.listen, options).await?;
+    println!("Node listening on {}{}", node.local_addr()?, secured);
+    println!("Node id: {}", node.node_id());
+
+    if let Some(port) = config.metrics_port {
+        let listener = TcpListener::bind((config.listen.ip(), port)).await?;
+        println!("Metrics at http://{}/metrics", listener.local_addr()?);
+        let metrics = node.metrics();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(listener, metrics).await {
+                println!("Metrics endpoint stopped: {}", e);
+            }
+        });
+    }
+
+    // Connect to any peers given on the command line
+    for peer in config.peers {
+        node.connect(peer);
+    }
+    // Discovery is a convenience; a node without it still takes --peer links
+    if config.mdns
+        && let Err(e) = discovery::start(node.dialer(), node.local_addr()?)
+    {
+        println!("Warning: mDNS discovery is off: {}", e);
+    }
+
+    node.run().await?;
+    Ok(())
+}
+
+// Run every node of a topology in this process until Ctrl-C. All nodes share
+// `genesis`; a topology with unreachable nodes still starts, with a warning.
+pub async fn run_topology(topology: Topology, genesis: Vec<(String, u64)>) -> Result<(), BoxError> {
+    let unreachable = topology.unreachable();
+    if !unreachable.is_empty() {
+        println!(
+            "Warning: nodes {:?} can't be reached from node {}; the network is split",
+            unreachable, topology.nodes[0].port
+        );
+    }
+
+    let options = NodeOptions {
+        genesis: Ledger::new(genesis),
+        ..NodeOptions::default()
+    };
+    let nodes = topology.start(&options).await?;
+    for (config, node) in topology.nodes.iter().zip(nodes) {
+        println!("Node {} listening on port {}", node.node_id(), config.port);
+        let port = config.port;
+        tokio::spawn(async move {
+            if let Err(e) = node.run().await {
+                println!("Node on port {} stopped: {}", port, e);
+            }
+        });
+    }
+
+    tokio::signal::ctrl_c().await?;
+    println!("Shutting down {} nodes", topology.nodes.len());
+    Ok(())
+}
+
+// Deliver one transaction to a running node, returning whether the node
+// accepted it. Frames on a connection are handled in order, so the lookup
+// only runs once the transaction has been checked.
+pub async fn send_transaction(
+    addr: &str,
+    transaction: Transaction,
+    tls: Option<&TlsOptions>,
+) -> Result<bool, BoxError> {
+    let tls = load_tls(tls, "p2p-client")?;
+    let mut client = Client::connect(addr, tls.as_ref()).await?;
+    let id = transaction.signature.clone();
+    client
+        .send(&Message::Transaction {
+            transaction,
+            hops: 0,
+        })
+        .await?;
+
+    let answer = client
+        .request(|request_id| Message::GetTx { request_id, id })
+        .await?;
+    client.close().await?;
+    match answer {
+        Message::Tx { .. } => Ok(true),
+        Message::NotFound { .. } => Ok(false),
+        other => Err(format!("unexpected answer from {}: {:?}", addr, other).into()),
+    }
+}
+
+// What `node status` reports about a running node
+pub struct NodeStatus {
+    pub transaction_count: usize,
+    pub dropped_relays: u64,
+    pub peers: Vec<PeerInfo>,
+    // Balance of the account asked about, None if the node has never seen it
+    pub balance: Option<u64>,
+    pub stats: NodeStats,
+}
+
+// Ask a running node for its transaction count, peers and traffic, and
+// optionally the balance of one account
+pub async fn query_status(
+    addr: &str,
+    tls: Option<&TlsOptions>,
+    account: Option<&str>,
+) -> Result<NodeStatus, BoxError> {
+    let tls = load_tls(tls, "p2p-client")?;
+    let mut client = Client::connect(addr, tls.as_ref()).await?;
+    let unexpected = |other| format!("unexpected answer from {}: {:?}", addr, other);
+
+    let (transaction_count, dropped_relays) = match client
+        .request(|request_id| Message::GetStatus { request_id })
+        .await?
+    {
+        Message::Status {
+            transaction_count,
+            dropped_relays,
+            ..
+        } => (transaction_count, dropped_relays),
+        other => return Err(unexpected(other).into()),
+    };
+    let peers = match client
+        .request(|request_id| Message::GetPeers { request_id })
+        .await?
+    {
+        Message::Peers { peers, .. } => peers,
+        other => return Err(unexpected(other).into()),
+    };
+    let stats = match client
+        .request(|request_id| Message::GetStats { request_id })
+        .await?
+    {
+        Message::Stats { stats, .. } => stats,
+        other => return Err(unexpected(other).into()),
+    };
+    let balance = match account {
+        Some(account) => {
+            let account = account.to_string();
+            match client
+                .request(|request_id| Message::GetBalance {
+                    request_id,
+                    account,
+                })
+                .await?
+            {
+                Message::Balance { balance, .. } => Some(balance),
+                Message::NotFound { .. } => None,
+                other => return Err(unexpected(other).into()),
+            }
+        }
+        None => None,
+    };
+
+    client.close().await?;
+    Ok(NodeStatus {
+        transaction_count,
+        dropped_relays,
+        peers,
+        balance,
+        stats,
+    })
+}
+
+// Read the node id from `dir`, creating the directory and id on first use
+fn load_node_id(dir: &Path) -> Result<String, BoxError> {
+    let path = dir.join("node_id");
+    match fs::read_to_string(&path) {
+        Ok(id) => Ok(id.trim().to_string()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            fs::create_dir_all(dir)?;
+            let id = keys::generate_node_id()?;
+            fs::write(&path, &id)?;
+            Ok(id)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn load_tls(options: Option<&TlsOptions>, name: &str) -> Result<Option<TlsContext>, BoxError> {
+    options
+        .map(|options| TlsContext::load(options, name))
+        .transpose()
+}
@@ -1,121 +1,370 @@
-use std::collections::HashMap;
-use std::error::Error;
-use std::sync::Arc;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{broadcast, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use serde::{Serialize, Deserialize};
-
-// Represent a transaction
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct Transaction {
-    from: String,
-    to: String,
-    amount: f64,
-    timestamp: u64,
+use std::net::SocketAddr;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::error::ErrorKind;
+use clap::{Args, CommandFactory, Parser, Subcommand};
+use p2p_solana_network_simulation::net::{
+    DEFAULT_KEEPALIVE, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_CONNECTIONS_PER_IP, DEFAULT_MAX_HOPS,
+    DEFAULT_RELAY_QUEUE_LEN,
+};
+use p2p_solana_network_simulation::protocol::{
+    TrafficStats, Transaction, decode_pubkey, encode_pubkey,
+};
+use p2p_solana_network_simulation::state::DEFAULT_MAX_PEERS;
+use p2p_solana_network_simulation::tls::TlsOptions;
+use p2p_solana_network_simulation::topology::Topology;
+use p2p_solana_network_simulation::{
+    BoxError, NodeConfig, keys, query_status, run, run_topology, send_transaction,
+};
+use tokio::runtime::{Builder, Runtime};
+
+// Runtime defaults, the same as Tokio's own
+const DEFAULT_MAX_BLOCKING_THREADS: usize = 512;
+const DEFAULT_STACK_SIZE: usize = 2 * 1024 * 1024;
+
+/// A peer-to-peer network of nodes relaying signed transactions over TCP
+#[derive(Parser)]
+#[command(name = "node")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
 }
 
-// Store node state
-struct NodeState {
-    transactions: HashMap<String, Vec<Transaction>>,
+#[derive(Subcommand)]
+enum Command {
+    /// Run a node and connect it to the given peers
+    Run(RunArgs),
+    /// Sign a transaction and send it to a running node
+    Send(SendArgs),
+    /// Print the peers and transaction count of a running node
+    Status(StatusArgs),
+    /// Generate a new keypair file
+    Keygen(KeygenArgs),
+    /// Run every node described in a topology file inside this process
+    Topology(TopologyArgs),
+}
+
+#[derive(Args)]
+struct RunArgs {
+    /// Port to listen on, on 127.0.0.1
+    #[arg(long, default_value_t = 8000)]
+    port: u16,
+    /// Address to listen on instead, such as 0.0.0.0:8000 or [::]:8000
+    #[arg(long, value_name = "ADDR", conflicts_with = "port")]
+    listen: Option<SocketAddr>,
+    /// Address of a peer to connect to; may be repeated
+    #[arg(long = "peer", value_name = "ADDR")]
     peers: Vec<String>,
+    /// Maximum number of peers to keep
+    #[arg(long, default_value_t = DEFAULT_MAX_PEERS)]
+    max_peers: usize,
+    /// Maximum number of inbound connections; more are told the node is busy and closed
+    #[arg(long, default_value_t = NonZeroUsize::new(DEFAULT_MAX_CONNECTIONS).unwrap())]
+    max_connections: NonZeroUsize,
+    /// Maximum number of inbound connections from a single IP address
+    #[arg(long, default_value_t = NonZeroUsize::new(DEFAULT_MAX_CONNECTIONS_PER_IP).unwrap())]
+    max_connections_per_ip: NonZeroUsize,
+    /// Seconds between keepalive pings; a peer that misses 3 in a row is dropped
+    #[arg(long, default_value_t = NonZeroU64::new(DEFAULT_KEEPALIVE.as_secs()).unwrap())]
+    keepalive_secs: NonZeroU64,
+    /// Relays after which a transaction is kept but no longer forwarded
+    #[arg(long, default_value_t = DEFAULT_MAX_HOPS)]
+    max_hops: u8,
+    /// Relayed transactions a peer may fall behind by before it is told to resync
+    #[arg(long, default_value_t = NonZeroUsize::new(DEFAULT_RELAY_QUEUE_LEN).unwrap())]
+    relay_queue_len: NonZeroUsize,
+    /// Starting balance for an account; may be repeated, and should match on every node
+    #[arg(long, value_name = "PUBKEY=AMOUNT", value_parser = parse_allocation)]
+    genesis: Vec<(String, u64)>,
+    /// Directory to keep the node id and transaction log in, so both survive restarts
+    #[arg(long, value_name = "PATH")]
+    data_dir: Option<PathBuf>,
+    /// Rewrite the data directory's transaction log from its replayed state before starting
+    #[arg(long, requires = "data_dir")]
+    compact: bool,
+    /// Serve Prometheus metrics at /metrics on this port of the listen address
+    #[arg(long, value_name = "PORT")]
+    metrics_port: Option<u16>,
+    /// Don't advertise this node or look for peers over mDNS
+    #[arg(long)]
+    no_mdns: bool,
+    #[command(flatten)]
+    runtime: RuntimeArgs,
+    #[command(flatten)]
+    tls: TlsArgs,
+}
+
+#[derive(Args)]
+struct SendArgs {
+    /// Address of the node to send to
+    #[arg(long, value_name = "ADDR")]
+    to: String,
+    /// Keypair file of the sender
+    #[arg(long, value_name = "PATH")]
+    from_key: PathBuf,
+    /// Base58 public key of the recipient
+    #[arg(long, value_name = "PUBKEY")]
+    recipient: String,
+    /// Amount to transfer, in whole units
+    #[arg(long)]
+    amount: u64,
+    #[command(flatten)]
+    tls: TlsArgs,
+}
+
+#[derive(Args)]
+struct StatusArgs {
+    /// Address of the node to query
+    #[arg(long, value_name = "ADDR")]
+    addr: String,
+    /// Also print the balance of this base58 public key
+    #[arg(long, value_name = "PUBKEY")]
+    balance: Option<String>,
+    #[command(flatten)]
+    tls: TlsArgs,
+}
+
+#[derive(Args)]
+struct KeygenArgs {
+    /// Where to write the keypair; an existing file is never overwritten
+    #[arg(long, value_name = "PATH")]
+    out: PathBuf,
+}
+
+#[derive(Args)]
+struct TopologyArgs {
+    /// YAML file listing each node's port and the ports of its peers
+    file: PathBuf,
+    /// Starting balance for an account on every node; may be repeated
+    #[arg(long, value_name = "PUBKEY=AMOUNT", value_parser = parse_allocation)]
+    genesis: Vec<(String, u64)>,
+    #[command(flatten)]
+    runtime: RuntimeArgs,
+}
+
+// Tokio runtime tuning for `run` and `topology`
+#[derive(Args)]
+struct RuntimeArgs {
+    /// Number of runtime worker threads [default: number of CPUs]
+    #[arg(long)]
+    workers: Option<NonZeroUsize>,
+    /// Cap on the blocking thread pool
+    #[arg(long, default_value_t = NonZeroUsize::new(DEFAULT_MAX_BLOCKING_THREADS).unwrap())]
+    max_blocking_threads: NonZeroUsize,
+    /// Stack size for runtime threads, in bytes
+    #[arg(long, default_value_t = NonZeroUsize::new(DEFAULT_STACK_SIZE).unwrap())]
+    stack_size: NonZeroUsize,
+    /// Use the current-thread executor for low-resource environments
+    #[arg(long, conflicts_with = "workers")]
+    single_threaded: bool,
+}
+
+impl RuntimeArgs {
+    fn build(&self) -> std::io::Result<Runtime> {
+        let mut builder = if self.single_threaded {
+            println!("Runtime: current-thread");
+            Builder::new_current_thread()
+        } else {
+            let workers = self
+                .workers
+                .or_else(|| std::thread::available_parallelism().ok())
+                .map_or(1, NonZeroUsize::get);
+            println!("Runtime: multi-thread with {} workers", workers);
+            let mut builder = Builder::new_multi_thread();
+            builder.worker_threads(workers);
+            builder
+        };
+        builder
+            .max_blocking_threads(self.max_blocking_threads.get())
+            .thread_stack_size(self.stack_size.get())
+            .enable_all()
+            .build()
+    }
+}
+
+// Mutual TLS is enabled as soon as --tls-ca is given
+#[derive(Args)]
+struct TlsArgs {
+    /// CA certificate used to verify peers; `run` generates it (with its key) if missing
+    #[arg(long, value_name = "PATH")]
+    tls_ca: Option<PathBuf>,
+    /// PEM certificate for this side [default: issued from the CA key for localhost and the listen IP]
+    #[arg(long, value_name = "PATH", requires_all = ["tls_ca", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key matching --tls-cert
+    #[arg(long, value_name = "PATH", requires_all = ["tls_ca", "tls_cert"])]
+    tls_key: Option<PathBuf>,
+}
+
+impl TlsArgs {
+    fn options(self) -> Option<TlsOptions> {
+        self.tls_ca.map(|ca| TlsOptions {
+            ca,
+            cert: self.tls_cert,
+            key: self.tls_key,
+        })
+    }
 }
 
-async fn handle_connection(
-    mut socket: TcpStream,
-    tx: broadcast::Sender<Transaction>,
-    state: Arc<Mutex<NodeState>>,
-) {
-    let mut buffer = [0u8; 1024];
-    
-    loop {
-        match socket.read(&mut buffer).await {
-            Ok(n) if n == 0 => {
-                println!("Connection closed");
-                break;
+fn main() -> Result<(), BoxError> {
+    match Cli::parse().command {
+        Command::Run(args) => {
+            if args.peers.len() > args.max_peers {
+                invalid(format!(
+                    "{} --peer addresses given but --max-peers is {}",
+                    args.peers.len(),
+                    args.max_peers
+                ));
             }
-            Ok(n) => {
-                if let Ok(transaction) = serde_json::from_slice::<Transaction>(&buffer[..n]) {
-                    println!("Received transaction: {:?}", transaction);
-                    
-                    // Store transaction
-                    let mut state = state.lock().await;
-                    state.transactions
-                        .entry(transaction.from.clone())
-                        .or_default()
-                        .push(transaction.clone());
-                    
-                    // Broadcast to other peers
-                    let _ = tx.send(transaction);
-                }
+            let listen = args
+                .listen
+                .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], args.port)));
+            let config = NodeConfig {
+                listen,
+                peers: args.peers,
+                max_peers: args.max_peers,
+                max_connections: args.max_connections.get(),
+                max_connections_per_ip: args.max_connections_per_ip.get(),
+                keepalive: Duration::from_secs(args.keepalive_secs.get()),
+                max_hops: args.max_hops,
+                relay_queue_len: args.relay_queue_len.get(),
+                genesis: args.genesis,
+                tls: args.tls.options(),
+                data_dir: args.data_dir,
+                compact: args.compact,
+                metrics_port: args.metrics_port,
+                mdns: !args.no_mdns,
+            };
+            args.runtime.build()?.block_on(run(config))
+        }
+        Command::Send(args) => {
+            if decode_pubkey(&args.recipient).is_none() {
+                invalid(format!(
+                    "--recipient {} is not a base58 public key",
+                    args.recipient
+                ));
             }
-            Err(e) => {
-                println!("Error reading from socket: {:?}", e);
-                break;
+            if args.amount == 0 {
+                invalid("--amount must be positive".to_string());
             }
-        }
-    }
-}
 
-async fn connect_to_peer(
-    addr: String,
-    tx: broadcast::Sender<Transaction>,
-    state: Arc<Mutex<NodeState>>,
-) {
-    match TcpStream::connect(&addr).await {
-        Ok(socket) => {
-            println!("Connected to peer: {}", addr);
+            let key = keys::read_keypair_file(&args.from_key)?;
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let transaction = Transaction::new_signed(&key, args.recipient, args.amount, timestamp);
+            let signature = transaction.signature.clone();
+
+            let tls = args.tls.options();
+            let accepted = client_runtime()?.block_on(send_transaction(
+                &args.to,
+                transaction,
+                tls.as_ref(),
+            ))?;
+            if !accepted {
+                return Err(format!(
+                    "{} rejected transaction {}; see its log for why",
+                    args.to, signature
+                )
+                .into());
+            }
+            println!("Sent transaction {}", signature);
+            Ok(())
+        }
+        Command::Status(args) => {
+            if let Some(account) = &args.balance
+                && decode_pubkey(account).is_none()
             {
-                let mut state = state.lock().await;
-                if !state.peers.contains(&addr) {
-                    state.peers.push(addr.clone());
+                invalid(format!("--balance {} is not a base58 public key", account));
+            }
+
+            let tls = args.tls.options();
+            let status = client_runtime()?.block_on(query_status(
+                &args.addr,
+                tls.as_ref(),
+                args.balance.as_deref(),
+            ))?;
+
+            let stats = &status.stats;
+            println!("Transactions: {}", status.transaction_count);
+            if status.dropped_relays > 0 {
+                println!("Dropped relays: {}", status.dropped_relays);
+            }
+            println!("Connections: {}", stats.active_connections);
+            println!("Traffic: {}", traffic_summary(&stats.total));
+            println!(
+                "Fanout: {} relays, mean {:.3} ms, max {:.3} ms",
+                stats.fanout.relays,
+                stats.fanout.mean_micros as f64 / 1000.0,
+                stats.fanout.max_micros as f64 / 1000.0
+            );
+            if let Some(account) = &args.balance {
+                match status.balance {
+                    Some(balance) => println!("Balance of {}: {}", account, balance),
+                    None => println!("Balance of {}: not found", account),
                 }
             }
-            handle_connection(socket, tx, state).await;
+            println!("Peers: {}", status.peers.len());
+            for peer in status.peers {
+                let rtt = match peer.rtt_micros {
+                    Some(micros) => format!("{:.3} ms", micros as f64 / 1000.0),
+                    None => "-".to_string(),
+                };
+                println!("  {} {} rtt {}", peer.node_id, peer.addr, rtt);
+                if let Some(peer) = stats.peers.iter().find(|p| p.node_id == peer.node_id) {
+                    println!("    {}", traffic_summary(&peer.traffic));
+                }
+            }
+            Ok(())
+        }
+        Command::Keygen(args) => {
+            let key = keys::generate()?;
+            keys::write_keypair_file(&args.out, &key)?;
+            println!("Wrote keypair to {}", args.out.display());
+            println!("Public key: {}", encode_pubkey(&key.verifying_key()));
+            Ok(())
+        }
+        Command::Topology(args) => {
+            let topology = Topology::load(&args.file)?;
+            args.runtime
+                .build()?
+                .block_on(run_topology(topology, args.genesis))
         }
-        Err(e) => println!("Failed to connect to peer {}: {:?}", addr, e),
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let port = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "8000".to_string())
-        .parse::<u16>()?;
-    
-    let state = Arc::new(Mutex::new(NodeState {
-        transactions: HashMap::new(),
-        peers: Vec::new(),
-    }));
-    
-    // Channel for broadcasting transactions
-    let (tx, _) = broadcast::channel(16);
-    let tx_clone = tx.clone();
-    
-    // Listen for incoming connections
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).await?;
-    println!("Node listening on port {}", port);
-    
-    // If a peer address is provided, connect to it
-    if let Some(peer_addr) = std::env::args().nth(2) {
-        let tx_peer = tx.clone();
-        let state_peer = state.clone();
-        tokio::spawn(async move {
-            connect_to_peer(peer_addr, tx_peer, state_peer).await;
-        });
-    }
-    
-    // Accept incoming connections
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        println!("New peer connected: {:?}", addr);
-        
-        let tx = tx_clone.clone();
-        let state = state.clone();
-        
-        tokio::spawn(async move {
-            handle_connection(socket, tx, state).await;
-        });
+fn parse_allocation(value: &str) -> Result<(String, u64), String> {
+    let (pubkey, amount) = value.split_once('=').ok_or("expected PUBKEY=AMOUNT")?;
+    if decode_pubkey(pubkey).is_none() {
+        return Err(format!("{} is not a base58 public key", pubkey));
     }
-}
\ No newline at end of file
+    let amount = amount
+        .parse()
+        .map_err(|e| format!("invalid amount {}: {}", amount, e))?;
+    Ok((pubkey.to_string(), amount))
+}
+
+// One line of frame and byte counts for `status`
+fn traffic_summary(traffic: &TrafficStats) -> String {
+    format!(
+        "in {} frames/{} bytes, out {} frames/{} bytes, {} invalid frames, {} rejected transactions",
+        traffic.frames_in,
+        traffic.bytes_in,
+        traffic.frames_out,
+        traffic.bytes_out,
+        traffic.invalid_frames,
+        traffic.rejected_transactions
+    )
+}
+
+// The one-shot subcommands don't need more than a single thread
+fn client_runtime() -> std::io::Result<Runtime> {
+    Builder::new_current_thread().enable_all().build()
+}
+
+// Report an argument combination clap can't express and exit
+fn invalid(message: String) -> ! {
+    Cli::command()
+        .error(ErrorKind::ArgumentConflict, message)
+        .exit()
+}
@@ -0,0 +1,333 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::protocol::{FanoutStats, NodeStats, PeerStats, TrafficStats};
+
+// Counters for one stream of traffic, either a whole node's or one peer's
+#[derive(Default)]
+pub struct Traffic {
+    frames_in: AtomicU64,
+    frames_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    invalid_frames: AtomicU64,
+    rejected_transactions: AtomicU64,
+}
+
+impl Traffic {
+    fn snapshot(&self) -> TrafficStats {
+        TrafficStats {
+            frames_in: self.frames_in.load(Ordering::Relaxed),
+            frames_out: self.frames_out.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            invalid_frames: self.invalid_frames.load(Ordering::Relaxed),
+            rejected_transactions: self.rejected_transactions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Everything a node counts about its connections
+#[derive(Default)]
+pub struct Metrics {
+    total: Traffic,
+    active_connections: AtomicU64,
+    // Traffic of each connected peer, by node id
+    peers: Mutex<HashMap<String, Arc<Traffic>>>,
+    relays: AtomicU64,
+    fanout_total_micros: AtomicU64,
+    fanout_max_micros: AtomicU64,
+}
+
+impl Metrics {
+    // Start counting a new connection; it stops counting as active when the
+    // meter is dropped
+    pub fn connection(self: &Arc<Self>) -> Meter {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        Meter {
+            metrics: self.clone(),
+            peer: None,
+        }
+    }
+
+    // Record how long a relayed transaction took to reach every connection
+    fn fanout(&self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        self.relays.fetch_add(1, Ordering::Relaxed);
+        self.fanout_total_micros
+            .fetch_add(micros, Ordering::Relaxed);
+        self.fanout_max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> NodeStats {
+        let peers = self.peers.lock().expect("metrics lock poisoned");
+        let mut peers: Vec<PeerStats> = peers
+            .iter()
+            .map(|(node_id, traffic)| PeerStats {
+                node_id: node_id.clone(),
+                traffic: traffic.snapshot(),
+            })
+            .collect();
+        peers.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+
+        let relays = self.relays.load(Ordering::Relaxed);
+        let total_micros = self.fanout_total_micros.load(Ordering::Relaxed);
+        NodeStats {
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            total: self.total.snapshot(),
+            peers,
+            fanout: FanoutStats {
+                relays,
+                mean_micros: total_micros.checked_div(relays).unwrap_or(0),
+                max_micros: self.fanout_max_micros.load(Ordering::Relaxed),
+            },
+        }
+    }
+}
+
+// A connection's view of the metrics: updates go to the node's totals and,
+// once the other side has registered as a peer, to that peer's counters
+pub struct Meter {
+    metrics: Arc<Metrics>,
+    peer: Option<(String, Arc<Traffic>)>,
+}
+
+impl Meter {
+    pub fn set_peer(&mut self, node_id: &str) {
+        let traffic = Arc::new(Traffic::default());
+        self.metrics
+            .peers
+            .lock()
+            .expect("metrics lock poisoned")
+            .insert(node_id.to_string(), traffic.clone());
+        self.peer = Some((node_id.to_string(), traffic));
+    }
+
+    fn count(&self, counter: impl Fn(&Traffic) -> &AtomicU64, amount: u64) {
+        counter(&self.metrics.total).fetch_add(amount, Ordering::Relaxed);
+        if let Some((_, traffic)) = &self.peer {
+            counter(traffic).fetch_add(amount, Ordering::Relaxed);
+        }
+    }
+
+    pub fn bytes_in(&self, len: usize) {
+        self.count(|t| &t.bytes_in, len as u64);
+    }
+
+    pub fn frame_in(&self) {
+        self.count(|t| &t.frames_in, 1);
+    }
+
+    pub fn frame_out(&self, len: usize) {
+        self.count(|t| &t.frames_out, 1);
+        self.count(|t| &t.bytes_out, len as u64);
+    }
+
+    pub fn invalid_frame(&self) {
+        self.count(|t| &t.invalid_frames, 1);
+    }
+
+    pub fn rejected_transaction(&self) {
+        self.count(|t| &t.rejected_transactions, 1);
+    }
+}
+
+impl Drop for Meter {
+    fn drop(&mut self) {
+        self.metrics
+            .active_connections
+            .fetch_sub(1, Ordering::Relaxed);
+        if let Some((node_id, traffic)) = &self.peer {
+            let mut peers = self.metrics.peers.lock().expect("metrics lock poisoned");
+            // A reconnect may already have replaced the entry
+            if peers
+                .get(node_id)
+                .is_some_and(|current| Arc::ptr_eq(current, traffic))
+            {
+                peers.remove(node_id);
+            }
+        }
+    }
+}
+
+// Tracks one relayed transaction until every peer connection has handled it.
+// Connections finish it as they write it out (or skip it), and the relaying
+// side adds the number of receivers once the broadcast returns it, so
+// whichever brings the count back to zero is the last.
+pub struct Fanout {
+    received: Instant,
+    pending: AtomicI64,
+    metrics: Arc<Metrics>,
+}
+
+impl Fanout {
+    pub fn new(received: Instant, metrics: Arc<Metrics>) -> Self {
+        Fanout {
+            received,
+            pending: AtomicI64::new(0),
+            metrics,
+        }
+    }
+
+    pub fn sent_to(&self, receivers: usize) {
+        let receivers = receivers as i64;
+        if self.pending.fetch_add(receivers, Ordering::AcqRel) == -receivers {
+            self.metrics.fanout(self.received.elapsed());
+        }
+    }
+
+    pub fn done(&self) {
+        if self.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.metrics.fanout(self.received.elapsed());
+        }
+    }
+}
+
+// Serve the node's metrics in the Prometheus text format at /metrics
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> io::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = answer_http(socket, &metrics).await {
+                println!("Metrics request failed: {}", e);
+            }
+        });
+    }
+}
+
+async fn answer_http(mut socket: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    // Only the request line matters; headers and bodies are ignored
+    let mut request = vec![0; 1024];
+    let len = socket.read(&mut request).await?;
+    let request = String::from_utf8_lossy(&request[..len]);
+    let (status, body) = if request.starts_with("GET /metrics ") {
+        ("200 OK", render(&metrics.snapshot()))
+    } else {
+        ("404 Not Found", "not found\n".to_string())
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+// A counter's name and how to read it from a TrafficStats
+type Series = (&'static str, fn(&TrafficStats) -> u64);
+
+fn render(stats: &NodeStats) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "p2p_active_connections {}", stats.active_connections);
+    let series: [Series; 6] = [
+        ("p2p_frames_in_total", |t| t.frames_in),
+        ("p2p_frames_out_total", |t| t.frames_out),
+        ("p2p_bytes_in_total", |t| t.bytes_in),
+        ("p2p_bytes_out_total", |t| t.bytes_out),
+        ("p2p_invalid_frames_total", |t| t.invalid_frames),
+        ("p2p_rejected_transactions_total", |t| {
+            t.rejected_transactions
+        }),
+    ];
+    for (name, value) in series {
+        let _ = writeln!(out, "{} {}", name, value(&stats.total));
+        for peer in &stats.peers {
+            let _ = writeln!(
+                out,
+                "{}{{peer=\"{}\"}} {}",
+                name,
+                escape_label(&peer.node_id),
+                value(&peer.traffic)
+            );
+        }
+    }
+    let _ = writeln!(out, "p2p_relays_total {}", stats.fanout.relays);
+    let _ = writeln!(
+        out,
+        "p2p_fanout_mean_seconds {}",
+        stats.fanout.mean_micros as f64 / 1e6
+    );
+    let _ = writeln!(
+        out,
+        "p2p_fanout_max_seconds {}",
+        stats.fanout.max_micros as f64 / 1e6
+    );
+    out
+}
+
+// Node ids come from the peers themselves, so quote them safely
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_per_peer_while_connected() {
+        let metrics = Arc::new(Metrics::default());
+        let mut meter = metrics.connection();
+        meter.frame_in();
+        meter.set_peer("peer1");
+        meter.frame_out(10);
+        meter.invalid_frame();
+
+        let stats = metrics.snapshot();
+        assert_eq!(stats.active_connections, 1);
+        assert_eq!(stats.total.frames_in, 1);
+        assert_eq!(stats.total.bytes_out, 10);
+        assert_eq!(stats.peers.len(), 1);
+        assert_eq!(stats.peers[0].traffic.frames_in, 0);
+        assert_eq!(stats.peers[0].traffic.frames_out, 1);
+        assert_eq!(stats.peers[0].traffic.invalid_frames, 1);
+
+        drop(meter);
+        let stats = metrics.snapshot();
+        assert_eq!(stats.active_connections, 0);
+        assert!(stats.peers.is_empty());
+        assert_eq!(stats.total.frames_out, 1);
+    }
+
+    #[test]
+    fn fanout_ends_with_the_last_connection_either_side_of_the_send() {
+        let metrics = Arc::new(Metrics::default());
+        let early = Fanout::new(Instant::now(), metrics.clone());
+        early.done();
+        early.done();
+        early.sent_to(2);
+
+        let late = Fanout::new(Instant::now(), metrics.clone());
+        late.sent_to(2);
+        late.done();
+        assert_eq!(metrics.snapshot().fanout.relays, 1);
+        late.done();
+        assert_eq!(metrics.snapshot().fanout.relays, 2);
+    }
+
+    #[test]
+    fn renders_prometheus_series() {
+        let metrics = Arc::new(Metrics::default());
+        let mut meter = metrics.connection();
+        meter.set_peer("peer1");
+        meter.bytes_in(42);
+
+        let text = render(&metrics.snapshot());
+        assert!(text.contains("p2p_active_connections 1\n"));
+        assert!(text.contains("p2p_bytes_in_total 42\n"));
+        assert!(text.contains("p2p_bytes_in_total{peer=\"peer1\"} 42\n"));
+    }
+}
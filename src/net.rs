@@ -0,0 +1,1218 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{Mutex, Notify, OwnedSemaphorePermit, Semaphore, broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tokio::time::{MissedTickBehavior, interval_at, sleep, timeout};
+
+use crate::BoxError;
+use crate::keys;
+use crate::ledger::Ledger;
+use crate::metrics::{Fanout, Meter, Metrics};
+use crate::protocol::{
+    CodecError, FrameCodec, MAX_SYNC_BATCH, MAX_SYNC_PAGE_LEN, Message, PROTOCOL_VERSION, PeerInfo,
+    Transaction,
+};
+use crate::state::{Admission, DEFAULT_MAX_PEERS, NodeState, PeerLink};
+use crate::tls::TlsContext;
+use crate::wal::Wal;
+
+// A transaction to relay, tagged with the connection it arrived on if it is
+// relayed as it arrives
+#[derive(Clone)]
+struct Relay {
+    origin: Option<u64>,
+    transaction: Transaction,
+    // Hop count to send it on with
+    hops: u8,
+    fanout: Arc<Fanout>,
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+// Default time a new connection has to send its Hello
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Default time between keepalive pings on each connection
+pub const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(15);
+
+// Default cap on simultaneous inbound connections
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+// Default cap on simultaneous inbound connections from one IP address
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 64;
+
+// Default number of relays after which a transaction is no longer forwarded
+pub const DEFAULT_MAX_HOPS: u8 = 6;
+
+// Default number of relayed transactions a peer connection may fall behind by
+pub const DEFAULT_RELAY_QUEUE_LEN: usize = 1024;
+
+// Pause after a failed accept, such as running out of file descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Unanswered pings in a row after which a connection is considered dead
+const MAX_MISSED_PONGS: u32 = 3;
+
+// Bounds on the delay before redialing a peer whose connection dropped
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+// Accepted transactions that can wait for the log writer before receivers block
+const WAL_QUEUE_LEN: usize = 64;
+
+// Settings for a node's listener and connections
+#[derive(Clone)]
+pub struct NodeOptions {
+    pub node_id: String,
+    pub tls: Option<TlsContext>,
+    pub max_peers: usize,
+    // Inbound connections beyond these limits are sent Busy and closed
+    pub max_connections: usize,
+    pub max_connections_per_ip: usize,
+    pub handshake_timeout: Duration,
+    pub keepalive: Duration,
+    // Transactions that arrive after this many relays are kept but not forwarded
+    pub max_hops: u8,
+    // Relays a peer connection may fall behind by; past that the oldest are
+    // dropped for it and the peer is asked to resync
+    pub relay_queue_len: usize,
+    // Starting balances; every node in a network should share the same one
+    pub genesis: Ledger,
+    // Write-ahead log replayed on startup and appended to for every accepted transaction
+    pub wal: Option<PathBuf>,
+    // Rewrite the log from the replayed state before starting
+    pub compact_wal: bool,
+}
+
+impl Default for NodeOptions {
+    fn default() -> Self {
+        NodeOptions {
+            node_id: keys::generate_node_id().expect("system randomness is available"),
+            tls: None,
+            max_peers: DEFAULT_MAX_PEERS,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_ip: DEFAULT_MAX_CONNECTIONS_PER_IP,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            keepalive: DEFAULT_KEEPALIVE,
+            max_hops: DEFAULT_MAX_HOPS,
+            relay_queue_len: DEFAULT_RELAY_QUEUE_LEN,
+            genesis: Ledger::default(),
+            wal: None,
+            compact_wal: false,
+        }
+    }
+}
+
+// What every connection task of a node shares
+struct Context {
+    node_id: String,
+    listen_port: u16,
+    handshake_timeout: Duration,
+    keepalive: Duration,
+    max_hops: u8,
+    tls: Option<TlsContext>,
+    tx: broadcast::Sender<Relay>,
+    state: Arc<Mutex<NodeState>>,
+    // Feeds the log writer thread. Only sent to while holding `state`, so the
+    // log holds transactions in the order they were first applied.
+    wal: Option<mpsc::Sender<Transaction>>,
+    // One permit per inbound connection slot
+    connections: Arc<Semaphore>,
+    // Open inbound connections by remote address
+    connections_per_ip: std::sync::Mutex<HashMap<IpAddr, usize>>,
+    max_connections_per_ip: usize,
+    metrics: Arc<Metrics>,
+}
+
+// A bound listener plus the state shared by all of its connections
+pub struct Node {
+    listener: TcpListener,
+    context: Arc<Context>,
+}
+
+impl Node {
+    pub async fn bind(addr: impl ToSocketAddrs, options: NodeOptions) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let (tx, _) = broadcast::channel(options.relay_queue_len);
+        let mut state = NodeState::new(options.max_peers, options.genesis);
+        let wal = match &options.wal {
+            Some(path) => {
+                let wal = replay_wal(path, options.compact_wal, &mut state)?;
+                let (records, queue) = mpsc::channel(WAL_QUEUE_LEN);
+                std::thread::Builder::new()
+                    .name("wal-writer".to_string())
+                    .spawn(move || write_wal(wal, queue))?;
+                Some(records)
+            }
+            None => None,
+        };
+        let context = Context {
+            node_id: options.node_id,
+            listen_port: listener.local_addr()?.port(),
+            handshake_timeout: options.handshake_timeout,
+            keepalive: options.keepalive,
+            max_hops: options.max_hops,
+            tls: options.tls,
+            tx,
+            state: Arc::new(Mutex::new(state)),
+            wal,
+            connections: Arc::new(Semaphore::new(options.max_connections)),
+            connections_per_ip: std::sync::Mutex::new(HashMap::new()),
+            max_connections_per_ip: options.max_connections_per_ip,
+            metrics: Arc::new(Metrics::default()),
+        };
+        Ok(Node {
+            listener,
+            context: Arc::new(context),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.context.node_id
+    }
+
+    pub fn state(&self) -> Arc<Mutex<NodeState>> {
+        self.context.state.clone()
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.context.metrics.clone()
+    }
+
+    // Dial a peer in the background
+    pub fn connect(&self, addr: String) {
+        self.dialer().connect(addr);
+    }
+
+    pub fn dialer(&self) -> Dialer {
+        Dialer {
+            context: self.context.clone(),
+        }
+    }
+
+    // Accept incoming connections forever. A failed accept, say from running
+    // out of file descriptors, only pauses the loop.
+    pub async fn run(self) -> io::Result<()> {
+        loop {
+            let (socket, addr) = match self.listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    println!("Failed to accept a connection: {}", e);
+                    sleep(ACCEPT_RETRY_DELAY).await;
+                    continue;
+                }
+            };
+            let slot = match ConnectionSlot::take(&self.context, addr.ip()) {
+                Ok(slot) => slot,
+                Err(reason) => {
+                    turn_away(socket, addr, reason, self.context.tls.is_some());
+                    continue;
+                }
+            };
+            println!("New peer connected: {:?}", addr);
+
+            let context = self.context.clone();
+
+            // With TLS enabled, plaintext peers fail the handshake and are dropped
+            tokio::spawn(async move {
+                let _slot = slot;
+                match context.tls.clone() {
+                    Some(tls) => match tls.accept(socket).await {
+                        Ok(stream) => {
+                            handle_connection(stream, addr, None, context).await;
+                        }
+                        Err(e) => println!("TLS handshake with {:?} failed: {}", addr, e),
+                    },
+                    None => {
+                        handle_connection(socket, addr, None, context).await;
+                    }
+                }
+            });
+        }
+    }
+}
+
+// Lets code outside the node, like discovery, dial peers after `run` has
+// taken the node
+#[derive(Clone)]
+pub struct Dialer {
+    context: Arc<Context>,
+}
+
+impl Dialer {
+    pub fn node_id(&self) -> &str {
+        &self.context.node_id
+    }
+
+    // Dial a peer in the background, redialing as for `Node::connect`. The
+    // task ends once the peer is given up on.
+    pub fn connect(&self, addr: String) -> JoinHandle<()> {
+        tokio::spawn(connect_to_peer(addr, self.context.clone()))
+    }
+
+    // Whether `node_id` isn't a peer yet and there is room for it
+    pub async fn wants_peer(&self, node_id: &str) -> bool {
+        self.context.state.lock().await.wants_peer(node_id)
+    }
+}
+
+// One of a node's inbound connection slots, given back when dropped
+struct ConnectionSlot {
+    _permit: OwnedSemaphorePermit,
+    ip: IpAddr,
+    context: Arc<Context>,
+}
+
+impl ConnectionSlot {
+    // Claim a slot for a connection from `ip`, or say which limit it is over
+    fn take(context: &Arc<Context>, ip: IpAddr) -> Result<Self, &'static str> {
+        let permit = context
+            .connections
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| "too many connections")?;
+        let mut per_ip = context
+            .connections_per_ip
+            .lock()
+            .expect("connection count lock poisoned");
+        let count = per_ip.entry(ip).or_default();
+        if *count >= context.max_connections_per_ip {
+            return Err("too many connections from your address");
+        }
+        *count += 1;
+        Ok(ConnectionSlot {
+            _permit: permit,
+            ip,
+            context: context.clone(),
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut per_ip = self
+            .context
+            .connections_per_ip
+            .lock()
+            .expect("connection count lock poisoned");
+        if let Some(count) = per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// Tell a connection over the limits why it's being closed, without spawning
+// anything: the Busy frame is small enough to go straight into the socket's
+// send buffer. TLS listeners just close, as the frame would arrive before any
+// TLS handshake and couldn't be read.
+fn turn_away(socket: TcpStream, addr: SocketAddr, reason: &str, tls: bool) {
+    println!("Turning away {}: {}", addr, reason);
+    if tls {
+        return;
+    }
+    let busy = Message::Busy {
+        reason: reason.to_string(),
+    };
+    let mut frame = BytesMut::new();
+    if FrameCodec::default().encode(&busy, &mut frame).is_ok()
+        && let Ok(mut socket) = socket.into_std()
+    {
+        // Still non-blocking, and a fresh socket's buffer has room
+        let _ = io::Write::write(&mut socket, &frame);
+    }
+}
+
+// Encode a message and write it as a single frame
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+) -> io::Result<()> {
+    writer.write_all(&encode_frame(message)?).await
+}
+
+fn encode_frame(message: &Message) -> io::Result<BytesMut> {
+    let mut frame = BytesMut::new();
+    FrameCodec::default()
+        .encode(message, &mut frame)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(frame)
+}
+
+// The write half of a node's connection, counting every frame sent
+struct FrameWriter<W> {
+    inner: W,
+    meter: Meter,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    async fn send(&mut self, message: &Message) -> io::Result<()> {
+        let frame = encode_frame(message)?;
+        self.inner.write_all(&frame).await?;
+        self.meter.frame_out(frame.len());
+        Ok(())
+    }
+
+    async fn shutdown(&mut self) -> io::Result<()> {
+        self.inner.shutdown().await
+    }
+}
+
+// The read half of a node's connection, keeping count of the bytes read since
+// the last `take_count`
+struct CountingReader<R> {
+    inner: R,
+    count: usize,
+}
+
+impl<R> CountingReader<R> {
+    fn take_count(&mut self) -> usize {
+        std::mem::take(&mut self.count)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.count += buf.filled().len() - before;
+        poll
+    }
+}
+
+// Read until a whole frame is buffered, returning None if the stream ends first
+async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    codec: &FrameCodec,
+    buffer: &mut BytesMut,
+) -> Result<Option<Message>, BoxError> {
+    loop {
+        match codec.decode(buffer) {
+            Ok(Some(message)) => return Ok(Some(message)),
+            Ok(None) => {}
+            Err(CodecError::FrameTooLarge { .. }) if looks_like_tls(buffer) => {
+                return Err("the other side is speaking TLS; pass --tls-ca to talk to it".into());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if reader.read_buf(buffer).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+// TLS records open with a content type of 21 (alert) or 22 (handshake) and a
+// major version of 3. As a length prefix those bytes would announce hundreds of
+// megabytes, so no valid frame starts this way.
+fn looks_like_tls(buffer: &[u8]) -> bool {
+    buffer.len() >= 2 && matches!(buffer[0], 21 | 22) && buffer[1] == 3
+}
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+// A short-lived connection used to send messages to a running node and read its replies
+pub struct Client {
+    stream: Box<dyn Stream>,
+    codec: FrameCodec,
+    buffer: BytesMut,
+    next_request_id: u64,
+}
+
+impl Client {
+    pub async fn connect(addr: &str, tls: Option<&TlsContext>) -> Result<Self, BoxError> {
+        let socket = TcpStream::connect(addr).await?;
+        let stream: Box<dyn Stream> = match tls {
+            Some(tls) => Box::new(tls.connect(addr, socket).await?),
+            None => Box::new(socket),
+        };
+        let mut client = Client {
+            stream,
+            codec: FrameCodec::default(),
+            buffer: BytesMut::with_capacity(4096),
+            next_request_id: 0,
+        };
+
+        client
+            .send(&Message::Hello {
+                node_id: keys::generate_node_id()?,
+                protocol_version: PROTOCOL_VERSION,
+                listen_port: 0,
+            })
+            .await?;
+        match client.recv().await? {
+            Some(Message::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                ..
+            }) => Ok(client),
+            Some(Message::Hello {
+                protocol_version, ..
+            }) => Err(format!(
+                "{} speaks protocol version {}, expected {}",
+                addr, protocol_version, PROTOCOL_VERSION
+            )
+            .into()),
+            Some(Message::Reject { reason }) => Err(format!("{} refused: {}", addr, reason).into()),
+            Some(Message::Busy { reason }) => Err(format!("{} is busy: {}", addr, reason).into()),
+            Some(other) => Err(format!("expected Hello from {}, got {:?}", addr, other).into()),
+            None => Err(format!("{} closed the connection during the handshake", addr).into()),
+        }
+    }
+
+    pub async fn send(&mut self, message: &Message) -> io::Result<()> {
+        write_message(&mut self.stream, message).await?;
+        self.stream.flush().await
+    }
+
+    // Read the next message, or None once the node closes the connection
+    pub async fn recv(&mut self) -> Result<Option<Message>, BoxError> {
+        read_message(&mut self.stream, &self.codec, &mut self.buffer).await
+    }
+
+    // Send the query built from a fresh request id and wait for its answer,
+    // skipping relayed transactions and anything else that arrives first
+    pub async fn request(
+        &mut self,
+        query: impl FnOnce(u64) -> Message,
+    ) -> Result<Message, BoxError> {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        self.send(&query(request_id)).await?;
+
+        while let Some(message) = self.recv().await? {
+            if message.response_id() == Some(request_id) {
+                return Ok(message);
+            }
+        }
+        Err("node closed the connection without answering".into())
+    }
+
+    pub async fn close(mut self) -> io::Result<()> {
+        self.stream.shutdown().await
+    }
+}
+
+// Ping bookkeeping for one connection
+#[derive(Default)]
+struct Keepalive {
+    next_nonce: u64,
+    // Nonce and send time of the latest unanswered ping
+    pending: Option<(u64, Instant)>,
+    missed: u32,
+}
+
+impl Keepalive {
+    // The next ping to send, or None once too many in a row went unanswered
+    fn ping(&mut self) -> Option<Message> {
+        if self.pending.is_some() {
+            self.missed += 1;
+        }
+        if self.missed >= MAX_MISSED_PONGS {
+            return None;
+        }
+        let nonce = self.next_nonce;
+        self.next_nonce += 1;
+        self.pending = Some((nonce, Instant::now()));
+        Some(Message::Ping { nonce })
+    }
+
+    // Any pong shows the peer is alive; only the latest ping yields a round trip
+    fn pong(&mut self, nonce: u64) -> Option<Duration> {
+        self.missed = 0;
+        match self.pending {
+            Some((pending, sent)) if pending == nonce => {
+                self.pending = None;
+                Some(sent.elapsed())
+            }
+            _ => None,
+        }
+    }
+}
+
+// Sync bookkeeping for one connection. Only one chain of SyncRequests runs at
+// a time; a Resync that arrives during one starts another once it ends.
+#[derive(Default)]
+struct Catchup {
+    running: bool,
+    again: bool,
+}
+
+impl Catchup {
+    // The request that starts a sync after the signature `after`
+    fn start(&mut self, after: Option<String>) -> Message {
+        self.running = true;
+        Message::SyncRequest { after }
+    }
+
+    // The peer says it dropped relays for us: sync again from `after`, where
+    // the last sync got to, as the peer applied everything it dropped later on
+    fn resync(&mut self, after: Option<String>) -> Option<Message> {
+        if self.running {
+            self.again = true;
+            return None;
+        }
+        Some(self.start(after))
+    }
+
+    // The last page of a sync arrived, leaving the sync mark at `after`
+    fn finished(&mut self, after: Option<String>) -> Option<Message> {
+        self.running = false;
+        if std::mem::take(&mut self.again) {
+            return Some(self.start(after));
+        }
+        None
+    }
+}
+
+// What a connection's frame handling keeps between frames
+struct Session<'a> {
+    id: u64,
+    // Node id of the other side, once it has registered as a peer
+    peer_id: Option<&'a str>,
+    keepalive: Keepalive,
+    catchup: Catchup,
+}
+
+// Where the last sync from the other side got to, if it is a peer
+async fn sync_mark(context: &Context, session: &Session<'_>) -> Option<String> {
+    let peer_id = session.peer_id?;
+    let state = context.state.lock().await;
+    state.sync_mark(peer_id).map(str::to_string)
+}
+
+// The next relay for a peer connection; connections without a subscription
+// never get one
+async fn next_relay(relay: &mut Option<broadcast::Receiver<Relay>>) -> Result<Relay, RecvError> {
+    match relay {
+        Some(relay) => relay.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+// Relays a connection will never write still count as handled
+fn abandon_relays(mut relay: broadcast::Receiver<Relay>) {
+    loop {
+        match relay.try_recv() {
+            Ok(relayed) => relayed.fanout.done(),
+            Err(TryRecvError::Lagged(_)) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+// `dialed` is the address we connected to for outbound connections. Returns
+// the peer's node id if the connection got as far as registering it.
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+    remote: SocketAddr,
+    dialed: Option<String>,
+    context: Arc<Context>,
+) -> Option<String> {
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let (reader, writer) = tokio::io::split(socket);
+    let mut reader = CountingReader {
+        inner: reader,
+        count: 0,
+    };
+    let mut writer = FrameWriter {
+        inner: writer,
+        meter: context.metrics.connection(),
+    };
+    let codec = FrameCodec::default();
+    let mut buffer = BytesMut::with_capacity(4096);
+
+    // Subscribe before the peer is registered, so a listed peer never misses a relay
+    let mut relay = Some(context.tx.subscribe());
+
+    let outbound = dialed.is_some();
+    let handshake = handshake(
+        &context,
+        &mut reader,
+        &mut writer,
+        &codec,
+        &mut buffer,
+        remote,
+        dialed,
+    );
+    let replaced = Arc::new(Notify::new());
+    let handshake = match handshake.await {
+        Ok(Some(peer)) => {
+            let dialer = if outbound {
+                context.node_id.clone()
+            } else {
+                peer.node_id.clone()
+            };
+            let link = PeerLink {
+                connection: id,
+                dialer,
+                outbound,
+                replaced: replaced.clone(),
+                released: Arc::new(Notify::new()),
+            };
+            register(&context, &mut writer, peer, link).await.map(Some)
+        }
+        other => other,
+    };
+    writer.meter.bytes_in(reader.take_count());
+    let peer = match handshake {
+        Ok(peer) => peer,
+        Err(e) => {
+            println!("Handshake with {} failed: {}", remote, e);
+            abandon_relays(relay.take().expect("subscribed above"));
+            return None;
+        }
+    };
+    match &peer {
+        Some(peer) => {
+            println!("Peer {} joined from {}", peer.node_id, peer.addr);
+            writer.meter.set_peer(&peer.node_id);
+        }
+        // Clients only query and submit, so aren't relayed to
+        None => abandon_relays(relay.take().expect("subscribed above")),
+    }
+    let mut session = Session {
+        id,
+        peer_id: peer.as_ref().map(|peer| peer.node_id.as_str()),
+        keepalive: Keepalive::default(),
+        catchup: Catchup::default(),
+    };
+
+    let mut ticks = interval_at(
+        tokio::time::Instant::now() + context.keepalive,
+        context.keepalive,
+    );
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // Catch up on anything the peer saw before we connected
+    let mut processed = Ok(());
+    if peer.is_some() {
+        let after = sync_mark(&context, &session).await;
+        let request = session.catchup.start(after);
+        processed = writer.send(&request).await.map_err(Into::into);
+    }
+
+    // Frames that arrived together with the Hello
+    if processed.is_ok() {
+        processed = process_frames(&codec, &mut buffer, &mut writer, &context, &mut session).await;
+    }
+
+    while processed.is_ok() {
+        tokio::select! {
+            read = reader.read_buf(&mut buffer) => match read {
+                Ok(0) => {
+                    println!("Connection closed");
+                    break;
+                }
+                Ok(_) => {
+                    writer.meter.bytes_in(reader.take_count());
+                    processed =
+                        process_frames(&codec, &mut buffer, &mut writer, &context, &mut session)
+                            .await;
+                }
+                Err(e) => {
+                    println!("Error reading from socket: {:?}", e);
+                    break;
+                }
+            },
+            relayed = next_relay(&mut relay) => match relayed {
+                Ok(relayed) => {
+                    // Don't echo a transaction back to the peer that sent it
+                    let written = if relayed.origin == Some(id) {
+                        Ok(())
+                    } else {
+                        let message = Message::Transaction {
+                            transaction: relayed.transaction,
+                            hops: relayed.hops,
+                        };
+                        writer.send(&message).await
+                    };
+                    relayed.fanout.done();
+                    if let Err(e) = written {
+                        println!("Error writing to socket: {:?}", e);
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    println!(
+                        "Connection fell behind, skipped {} transactions; asking the peer to resync",
+                        skipped
+                    );
+                    context.state.lock().await.record_dropped_relays(skipped);
+                    if let Err(e) = writer.send(&Message::Resync { skipped }).await {
+                        println!("Error writing to socket: {:?}", e);
+                        break;
+                    }
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = replaced.notified() => {
+                println!("Peer connected again over another connection, closing this one");
+                break;
+            }
+            _ = ticks.tick() => {
+                let Some(ping) = session.keepalive.ping() else {
+                    println!("No pong after {} pings, closing connection", MAX_MISSED_PONGS);
+                    break;
+                };
+                if let Err(e) = writer.send(&ping).await {
+                    println!("Error writing to socket: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+    if let Err(e) = processed {
+        println!("Closing connection: {}", e);
+    }
+    if let Some(relay) = relay {
+        abandon_relays(relay);
+    }
+
+    let peer = peer?;
+    context.state.lock().await.remove_peer(&peer.node_id, id);
+    println!("Peer {} disconnected", peer.node_id);
+    Some(peer.node_id)
+}
+
+// Exchange Hello frames and work out who the remote side is. Returns None for
+// clients, which don't accept connections and so aren't peers.
+async fn handshake<R, W>(
+    context: &Context,
+    reader: &mut R,
+    writer: &mut FrameWriter<W>,
+    codec: &FrameCodec,
+    buffer: &mut BytesMut,
+    remote: SocketAddr,
+    dialed: Option<String>,
+) -> Result<Option<PeerInfo>, BoxError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let hello = Message::Hello {
+        node_id: context.node_id.clone(),
+        protocol_version: PROTOCOL_VERSION,
+        listen_port: context.listen_port,
+    };
+    writer.send(&hello).await?;
+
+    let first = timeout(
+        context.handshake_timeout,
+        read_message(reader, codec, buffer),
+    )
+    .await
+    .map_err(|_| "no Hello before the handshake timeout")??;
+    if first.is_some() {
+        writer.meter.frame_in();
+    }
+
+    let (node_id, listen_port) = match first {
+        Some(Message::Hello {
+            node_id,
+            protocol_version,
+            listen_port,
+        }) => {
+            if protocol_version != PROTOCOL_VERSION {
+                let reason = format!(
+                    "unsupported protocol version {}, this node speaks {}",
+                    protocol_version, PROTOCOL_VERSION
+                );
+                return Err(refuse(writer, reason).await);
+            }
+            (node_id, listen_port)
+        }
+        Some(Message::Reject { reason }) => return Err(format!("refused: {}", reason).into()),
+        Some(Message::Busy { reason }) => return Err(format!("busy: {}", reason).into()),
+        Some(other) => return Err(format!("expected Hello, got {:?}", other).into()),
+        None => return Err("connection closed during the handshake".into()),
+    };
+
+    if node_id == context.node_id {
+        return Err("connected to ourselves".into());
+    }
+    if listen_port == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(PeerInfo {
+        node_id,
+        addr: dialed.unwrap_or_else(|| SocketAddr::new(remote.ip(), listen_port).to_string()),
+        rtt_micros: None,
+    }))
+}
+
+// Add a peer that completed the handshake to the node's peer list, or refuse
+// it. An accepted connection that should take over from the peer's current one
+// waits up to the handshake timeout for it to end, as it does when the peer
+// settles a simultaneous dial; a live connection is never pushed out by one.
+async fn register<W: AsyncWrite + Unpin>(
+    context: &Context,
+    writer: &mut FrameWriter<W>,
+    peer: PeerInfo,
+    link: PeerLink,
+) -> Result<PeerInfo, BoxError> {
+    let mut state = context.state.lock().await;
+    let mut admission = state.add_peer(peer.clone(), link.clone());
+    if let Admission::Wait(released) = admission {
+        // Created under the lock, so the release can't slip past it
+        let released = released.notified();
+        drop(state);
+        let _ = timeout(context.handshake_timeout, released).await;
+        state = context.state.lock().await;
+        admission = state.add_peer(peer.clone(), link);
+    }
+    drop(state);
+
+    if !matches!(admission, Admission::Added) {
+        let reason = "already connected or peer limit reached".to_string();
+        return Err(refuse(writer, reason).await);
+    }
+    Ok(peer)
+}
+
+// Tell the other side why we're hanging up, returning the reason as an error
+async fn refuse<W: AsyncWrite + Unpin>(writer: &mut FrameWriter<W>, reason: String) -> BoxError {
+    let _ = writer
+        .send(&Message::Reject {
+            reason: reason.clone(),
+        })
+        .await;
+    let _ = writer.shutdown().await;
+    reason.into()
+}
+
+// Handle every complete frame in the buffer; only framing and write errors end the connection
+async fn process_frames<W: AsyncWrite + Unpin>(
+    codec: &FrameCodec,
+    buffer: &mut BytesMut,
+    writer: &mut FrameWriter<W>,
+    context: &Context,
+    session: &mut Session<'_>,
+) -> Result<(), BoxError> {
+    let id = session.id;
+    loop {
+        let decoded = codec.decode(buffer);
+        match &decoded {
+            Ok(Some(_)) => writer.meter.frame_in(),
+            Ok(None) => {}
+            Err(_) => writer.meter.invalid_frame(),
+        }
+        match decoded {
+            Ok(Some(Message::Transaction { transaction, hops })) => {
+                let hold = session.peer_id.is_some();
+                receive_transaction(id, transaction, hops, hold, context, &writer.meter).await;
+            }
+            Ok(Some(Message::SyncRequest { after })) => {
+                let (transactions, more) = context.state.lock().await.sync_page(
+                    after.as_deref(),
+                    MAX_SYNC_BATCH,
+                    MAX_SYNC_PAGE_LEN,
+                );
+                let response = Message::SyncResponse { transactions, more };
+                writer.send(&response).await?;
+            }
+            Ok(Some(Message::SyncResponse { transactions, more })) => {
+                let last = transactions.last().map(|last| last.signature.clone());
+                // A sync page comes straight from the peer that holds it
+                for transaction in transactions {
+                    receive_transaction(id, transaction, 0, true, context, &writer.meter).await;
+                }
+                // Later syncs from this peer, over this connection or the
+                // next, carry on from here
+                if let (Some(peer_id), Some(last)) = (session.peer_id, &last) {
+                    context
+                        .state
+                        .lock()
+                        .await
+                        .set_sync_mark(peer_id, last.clone());
+                }
+                let next = match (more, last) {
+                    (true, Some(after)) => Some(Message::SyncRequest { after: Some(after) }),
+                    _ => {
+                        let after = sync_mark(context, session).await;
+                        session.catchup.finished(after)
+                    }
+                };
+                if let Some(next) = next {
+                    writer.send(&next).await?;
+                }
+            }
+            Ok(Some(Message::Resync { skipped })) => {
+                println!("Peer dropped {} relays for us, syncing again", skipped);
+                let after = sync_mark(context, session).await;
+                if let Some(request) = session.catchup.resync(after) {
+                    writer.send(&request).await?;
+                }
+            }
+            Ok(Some(Message::Ping { nonce })) => {
+                writer.send(&Message::Pong { nonce }).await?;
+            }
+            Ok(Some(Message::Pong { nonce })) => {
+                if let (Some(rtt), Some(peer_id)) = (session.keepalive.pong(nonce), session.peer_id)
+                {
+                    context.state.lock().await.set_peer_rtt(peer_id, rtt);
+                }
+            }
+            Ok(Some(Message::GetStats { request_id })) => {
+                let stats = context.metrics.snapshot();
+                writer.send(&Message::Stats { request_id, stats }).await?;
+            }
+            Ok(Some(message)) => {
+                let answer = answer_query(&message, &*context.state.lock().await);
+                match answer {
+                    Some(answer) => writer.send(&answer).await?,
+                    None => println!("Ignoring unexpected message: {:?}", message),
+                }
+            }
+            Ok(None) => return Ok(()),
+            Err(CodecError::Json(e)) => println!("Dropping malformed message: {}", e),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Answer a query from the node's state, or None if `message` isn't a query
+fn answer_query(message: &Message, state: &NodeState) -> Option<Message> {
+    let answer = match message {
+        &Message::GetStatus { request_id } => Message::Status {
+            request_id,
+            transaction_count: state.transaction_count(),
+            dropped_relays: state.dropped_relays(),
+        },
+        &Message::GetPeers { request_id } => Message::Peers {
+            request_id,
+            peers: state.peers().cloned().collect(),
+        },
+        Message::GetTx { request_id, id } => match state.transaction(id) {
+            Some(transaction) => Message::Tx {
+                request_id: *request_id,
+                transaction: transaction.clone(),
+            },
+            None => Message::NotFound {
+                request_id: *request_id,
+            },
+        },
+        Message::GetBalance {
+            request_id,
+            account,
+        } => match state.ledger().get(account) {
+            Some(balance) => Message::Balance {
+                request_id: *request_id,
+                account: account.clone(),
+                balance,
+            },
+            None => Message::NotFound {
+                request_id: *request_id,
+            },
+        },
+        _ => return None,
+    };
+    Some(answer)
+}
+
+// Rebuild state from the log at `path`, optionally compacting it
+fn replay_wal(path: &Path, compact: bool, state: &mut NodeState) -> io::Result<Wal> {
+    let (wal, logged) = Wal::open(path)?;
+    let mut replayed = Vec::with_capacity(logged.len());
+    for transaction in logged {
+        // Everything logged applied once, so hold what no longer does
+        match state.record_transaction(transaction.clone(), 0, true) {
+            Ok(recorded) if recorded.new => replayed.push(transaction),
+            Ok(_) => {}
+            Err(e) => println!(
+                "Skipping logged transaction {}: {}",
+                transaction.signature, e
+            ),
+        }
+    }
+    println!(
+        "Replayed {} transactions from {}",
+        replayed.len(),
+        path.display()
+    );
+
+    if !compact {
+        return Ok(wal);
+    }
+    drop(wal);
+    let wal = Wal::rewrite(path, &replayed)?;
+    println!("Compacted {}", path.display());
+    Ok(wal)
+}
+
+// Append queued transactions to the log, syncing whenever the queue runs dry
+// so the fsyncs stay off the runtime and batch up under load. Runs on its own
+// thread until the node is dropped.
+fn write_wal(mut wal: Wal, mut queue: mpsc::Receiver<Transaction>) {
+    while let Some(transaction) = queue.blocking_recv() {
+        let mut next = Some(transaction);
+        while let Some(transaction) = next {
+            if let Err(e) = wal.append(&transaction) {
+                println!("Failed to log transaction {}: {}", transaction.signature, e);
+            }
+            next = queue.try_recv().ok();
+        }
+        if let Err(e) = wal.sync() {
+            println!("Failed to sync the write-ahead log: {}", e);
+        }
+    }
+}
+
+// Verify, record and relay a transaction from a client, a peer or a sync page.
+// `hops` is how many relays it has been through; at the node's limit it is
+// recorded without being forwarded. One that doesn't apply yet is held when
+// `hold` is set, as peers may relay it ahead of the transactions funding it,
+// and rejected otherwise. Whatever applies for the first time is relayed.
+async fn receive_transaction(
+    id: u64,
+    transaction: Transaction,
+    hops: u8,
+    hold: bool,
+    context: &Context,
+    meter: &Meter,
+) {
+    let received = Instant::now();
+    if let Err(problem) = transaction.check() {
+        println!("Dropping transaction, as {}: {:?}", problem, transaction);
+        meter.rejected_transaction();
+        return;
+    }
+    println!("Received transaction: {:?}", transaction);
+
+    // Store transaction, and only relay it the first time it applies
+    let mut state = context.state.lock().await;
+    let recorded = state.record_transaction(transaction.clone(), hops, hold);
+    if let (Ok(recorded), Some(wal)) = (&recorded, &context.wal) {
+        for (accepted, _) in &recorded.accepted {
+            if wal.send(accepted.clone()).await.is_err() {
+                println!(
+                    "Failed to log transaction {}: the log writer stopped",
+                    accepted.signature
+                );
+            }
+        }
+    }
+    drop(state);
+
+    let recorded = match recorded {
+        Ok(recorded) => recorded,
+        Err(e) => {
+            println!("Rejecting transaction {}: {}", transaction.signature, e);
+            meter.rejected_transaction();
+            return;
+        }
+    };
+    if recorded.new
+        && recorded.accepted.first().map(|(t, _)| &t.signature) != Some(&transaction.signature)
+    {
+        println!(
+            "Holding transaction {} until its sender can cover it",
+            transaction.signature
+        );
+    }
+    for (accepted, hops) in recorded.accepted {
+        if hops >= context.max_hops {
+            println!(
+                "Not relaying transaction {} after {} hops",
+                accepted.signature, hops
+            );
+            continue;
+        }
+        // Held transactions go to every peer, as the one that sent them may
+        // still be waiting on them too
+        let origin = (accepted.signature == transaction.signature).then_some(id);
+        let fanout = Arc::new(Fanout::new(received, context.metrics.clone()));
+        let relay = Relay {
+            origin,
+            transaction: accepted,
+            hops: hops + 1,
+            fanout: fanout.clone(),
+        };
+        // Without peers to write to, the fanout is over straight away
+        let receivers = context.tx.send(relay).unwrap_or(0);
+        fanout.sent_to(receivers);
+    }
+}
+
+// Dial a peer, and keep redialing with backoff whenever an established
+// connection drops. Gives up if the first dial fails or the peer refuses us,
+// and once the peer is connected over some other connection, whose dialer
+// takes over redialing it. Later refusals are retried, as the peer may not
+// have noticed the old connection is dead yet.
+async fn connect_to_peer(addr: String, context: Arc<Context>) {
+    let mut delay = RECONNECT_MIN_DELAY;
+    // Node id of the peer, once a connection to it has been registered
+    let mut peer_id = None;
+    loop {
+        let registered = match dial(&addr, &context).await {
+            Ok(registered) => registered,
+            Err(e) => {
+                println!("Failed to connect to peer {}: {}", addr, e);
+                None
+            }
+        };
+        match registered {
+            Some(node_id) => {
+                peer_id = Some(node_id);
+                delay = RECONNECT_MIN_DELAY;
+            }
+            None if peer_id.is_none() => return,
+            None => {}
+        }
+
+        println!("Reconnecting to peer {} in {:?}", addr, delay);
+        sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+
+        // Checked after the pause, as a connection taking over from ours may
+        // only be registered once ours has gone
+        if let Some(node_id) = &peer_id
+            && context.state.lock().await.has_peer(node_id)
+        {
+            println!(
+                "Peer {} is connected over another connection, no longer dialing {}",
+                node_id, addr
+            );
+            return;
+        }
+    }
+}
+
+// One outbound connection, returning the peer's node id if it was registered
+async fn dial(addr: &str, context: &Arc<Context>) -> Result<Option<String>, BoxError> {
+    let socket = TcpStream::connect(addr).await?;
+    let remote = socket.peer_addr()?;
+    println!("Connected to peer: {}", addr);
+
+    let dialed = Some(addr.to_string());
+    Ok(match context.tls.clone() {
+        Some(tls) => {
+            let stream = tls.connect(addr, socket).await?;
+            handle_connection(stream, remote, dialed, context.clone()).await
+        }
+        None => handle_connection(socket, remote, dialed, context.clone()).await,
+    })
+}
@@ -0,0 +1,210 @@
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, Issuer, KeyPair,
+    KeyUsagePurpose,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream as ClientTlsStream;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{self, ClientConfig, InvalidMessage, RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream as ServerTlsStream;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::BoxError;
+use crate::keys::write_private;
+
+// Paths given on the command line with --tls-ca, --tls-cert and --tls-key
+pub struct TlsOptions {
+    pub ca: PathBuf,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+// Acceptor and connector sharing one CA, both requiring a peer certificate
+#[derive(Clone)]
+pub struct TlsContext {
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+}
+
+impl TlsContext {
+    // Set up a node's side of TLS, generating the CA first if it doesn't exist
+    // yet. An issued certificate also names `listen`, unless that is a
+    // wildcard address.
+    pub fn for_node(
+        options: &TlsOptions,
+        node_name: &str,
+        listen: IpAddr,
+    ) -> Result<Self, BoxError> {
+        if !options.ca.exists() {
+            let ca_key_path = options.ca.with_extension("key");
+            generate_ca(&options.ca, &ca_key_path)?;
+            println!(
+                "Generated TLS CA at {} (key at {})",
+                options.ca.display(),
+                ca_key_path.display()
+            );
+        }
+        let mut names = NODE_SUBJECT_ALT_NAMES.map(str::to_string).to_vec();
+        if !listen.is_unspecified() && !names.contains(&listen.to_string()) {
+            names.push(listen.to_string());
+        }
+        Self::build(options, node_name, names)
+    }
+
+    // Set up a client's side of TLS from an existing CA
+    pub fn load(options: &TlsOptions, node_name: &str) -> Result<Self, BoxError> {
+        if !options.ca.exists() {
+            return Err(format!("CA {} not found", options.ca.display()).into());
+        }
+        Self::build(
+            options,
+            node_name,
+            NODE_SUBJECT_ALT_NAMES.map(str::to_string).to_vec(),
+        )
+    }
+
+    fn build(options: &TlsOptions, node_name: &str, names: Vec<String>) -> Result<Self, BoxError> {
+        let ca_key_path = options.ca.with_extension("key");
+        let ca_pem = fs::read_to_string(&options.ca)?;
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(ca_pem.as_bytes()) {
+            roots.add(cert?)?;
+        }
+        let roots = Arc::new(roots);
+
+        let (chain, key) = match (&options.cert, &options.key) {
+            (Some(cert), Some(key)) => (
+                CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?,
+                PrivateKeyDer::from_pem_file(key)?,
+            ),
+            (None, None) => {
+                if !ca_key_path.exists() {
+                    return Err(format!(
+                        "no --tls-cert/--tls-key given and CA key {} not found",
+                        ca_key_path.display()
+                    )
+                    .into());
+                }
+                issue_node_cert(
+                    &ca_pem,
+                    &fs::read_to_string(&ca_key_path)?,
+                    node_name,
+                    names,
+                )?
+            }
+            _ => return Err("--tls-cert and --tls-key must be given together".into()),
+        };
+
+        let provider = Arc::new(ring::default_provider());
+
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(roots.clone(), provider.clone()).build()?;
+        let server_config = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(chain.clone(), key.clone_key())?;
+
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(chain, key)?;
+
+        Ok(TlsContext {
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+            connector: TlsConnector::from(Arc::new(client_config)),
+        })
+    }
+
+    pub async fn accept(&self, socket: TcpStream) -> io::Result<ServerTlsStream<TcpStream>> {
+        self.acceptor
+            .accept(socket)
+            .await
+            .map_err(explain_plaintext)
+    }
+
+    pub async fn connect(
+        &self,
+        addr: &str,
+        socket: TcpStream,
+    ) -> Result<ClientTlsStream<TcpStream>, BoxError> {
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let server_name = ServerName::try_from(host.to_string())?;
+        Ok(self
+            .connector
+            .connect(server_name, socket)
+            .await
+            .map_err(explain_plaintext)?)
+    }
+}
+
+// A plaintext peer's first frame isn't a TLS record, which rustls reports as an
+// invalid content type; say what that most likely means
+fn explain_plaintext(e: io::Error) -> io::Error {
+    let plaintext = e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+        .is_some_and(|inner| {
+            matches!(
+                inner,
+                rustls::Error::InvalidMessage(InvalidMessage::InvalidContentType)
+            )
+        });
+    if !plaintext {
+        return e;
+    }
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "the other side is not speaking TLS; is it running without --tls-ca?",
+    )
+}
+
+// Names every issued certificate is valid for, whatever address it listens on
+const NODE_SUBJECT_ALT_NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+fn generate_ca(cert_path: &Path, key_path: &Path) -> Result<(), BoxError> {
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(Vec::new())?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "p2p-node CA");
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let cert = params.self_signed(&key)?;
+
+    write_private(key_path, key.serialize_pem().as_bytes())?;
+    fs::write(cert_path, cert.pem())?;
+    Ok(())
+}
+
+fn issue_node_cert(
+    ca_pem: &str,
+    ca_key_pem: &str,
+    node_name: &str,
+    names: Vec<String>,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), BoxError> {
+    let issuer = Issuer::from_ca_cert_pem(ca_pem, KeyPair::from_pem(ca_key_pem)?)?;
+
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(names)?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, node_name);
+    params.extended_key_usages = vec![
+        ExtendedKeyUsagePurpose::ServerAuth,
+        ExtendedKeyUsagePurpose::ClientAuth,
+    ];
+    let cert = params.signed_by(&key, &issuer)?;
+
+    let key = PrivateKeyDer::try_from(key.serialize_der())?;
+    Ok((vec![cert.der().clone()], key))
+}
@@ -0,0 +1,161 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::BoxError;
+use crate::keys;
+use crate::net::{Node, NodeOptions};
+
+// A whole network to run inside one process, as read from a YAML file:
+//
+//   nodes:
+//     - port: 8000
+//       peers: [8001]
+//
+// Nodes listen on 127.0.0.1 and name their peers by port. A link only needs
+// listing on one side, since connections carry traffic both ways.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Topology {
+    pub nodes: Vec<TopologyNode>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TopologyNode {
+    pub port: u16,
+    #[serde(default)]
+    pub peers: Vec<u16>,
+}
+
+impl Topology {
+    pub fn load(path: &Path) -> Result<Topology, BoxError> {
+        let contents = fs::read_to_string(path)?;
+        let topology: Topology = serde_yaml::from_str(&contents)
+            .map_err(|e| format!("invalid topology {}: {}", path.display(), e))?;
+        topology
+            .validate()
+            .map_err(|e| format!("invalid topology {}: {}", path.display(), e))?;
+        Ok(topology)
+    }
+
+    // Reject files that couldn't be started as written
+    pub fn validate(&self) -> Result<(), String> {
+        if self.nodes.is_empty() {
+            return Err("no nodes".to_string());
+        }
+        let mut ports = HashSet::new();
+        for node in &self.nodes {
+            if node.port == 0 {
+                return Err("port 0 can't be named as a peer".to_string());
+            }
+            if !ports.insert(node.port) {
+                return Err(format!("port {} is used by more than one node", node.port));
+            }
+        }
+        for node in &self.nodes {
+            for peer in &node.peers {
+                if *peer == node.port {
+                    return Err(format!("node {} lists itself as a peer", node.port));
+                }
+                if !ports.contains(peer) {
+                    return Err(format!(
+                        "node {} lists peer {}, which isn't in the topology",
+                        node.port, peer
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Ports of the nodes that can't be reached from the first node, following
+    // links in either direction
+    pub fn unreachable(&self) -> Vec<u16> {
+        let mut links: HashMap<u16, Vec<u16>> = HashMap::new();
+        for node in &self.nodes {
+            for &peer in &node.peers {
+                links.entry(node.port).or_default().push(peer);
+                links.entry(peer).or_default().push(node.port);
+            }
+        }
+
+        let mut reached = HashSet::new();
+        let mut queue: VecDeque<u16> = self
+            .nodes
+            .first()
+            .map(|node| node.port)
+            .into_iter()
+            .collect();
+        while let Some(port) = queue.pop_front() {
+            if reached.insert(port) {
+                queue.extend(links.get(&port).into_iter().flatten());
+            }
+        }
+        self.nodes
+            .iter()
+            .map(|node| node.port)
+            .filter(|port| !reached.contains(port))
+            .collect()
+    }
+
+    // Bind every node, then dial the configured peers. Each node gets a copy
+    // of `options` with its own node id. Returns the nodes in file order,
+    // ready to run.
+    pub async fn start(&self, options: &NodeOptions) -> Result<Vec<Node>, BoxError> {
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            let options = NodeOptions {
+                node_id: keys::generate_node_id()?,
+                ..options.clone()
+            };
+            let bound = Node::bind(("127.0.0.1", node.port), options)
+                .await
+                .map_err(|e| format!("could not listen on port {}: {}", node.port, e))?;
+            nodes.push(bound);
+        }
+        for (node, bound) in self.nodes.iter().zip(&nodes) {
+            for peer in &node.peers {
+                bound.connect(format!("127.0.0.1:{}", peer));
+            }
+        }
+        Ok(nodes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> Topology {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn finds_nodes_cut_off_from_the_first() {
+        let topology = parse(
+            "nodes:
+  - {port: 8000, peers: [8001]}
+  - {port: 8001}
+  - {port: 8002, peers: [8003]}
+  - {port: 8003}
+",
+        );
+        assert_eq!(topology.validate(), Ok(()));
+        assert_eq!(topology.unreachable(), [8002, 8003]);
+    }
+
+    #[test]
+    fn rejects_unknown_and_duplicate_ports() {
+        let unknown = parse("nodes: [{port: 8000, peers: [9000]}]");
+        assert!(unknown.validate().unwrap_err().contains("9000"));
+
+        let duplicate = parse("nodes: [{port: 8000}, {port: 8000}]");
+        assert!(duplicate.validate().unwrap_err().contains("more than one"));
+
+        let itself = parse("nodes: [{port: 8000, peers: [8000]}]");
+        assert!(itself.validate().unwrap_err().contains("itself"));
+    }
+}
@@ -0,0 +1,240 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::protocol::{MAX_FRAME_LEN, Transaction};
+
+// Records are synced to disk once this many are waiting, or sooner by `sync`
+const SYNC_BATCH: usize = 64;
+
+const HEADER_LEN: usize = 8;
+
+// Append-only log of accepted transactions. Each record is a 4-byte
+// big-endian body length, a 4-byte CRC-32 of the body, then the body as JSON.
+pub struct Wal {
+    file: BufWriter<File>,
+    unsynced: usize,
+}
+
+impl Wal {
+    // Open or create the log at `path`, returning it with every intact record
+    // in the order they were written. A corrupt or partially written tail is
+    // cut off, so later appends follow the last good record.
+    pub fn open(path: &Path) -> io::Result<(Wal, Vec<Transaction>)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let contents = fs::read(path)?;
+        let (transactions, good_len) = decode_records(&contents);
+        if good_len < contents.len() {
+            println!(
+                "Truncating {} corrupt bytes from the end of {}",
+                contents.len() - good_len,
+                path.display()
+            );
+            file.set_len(good_len as u64)?;
+            file.sync_data()?;
+        }
+        let wal = Wal {
+            file: BufWriter::new(file),
+            unsynced: 0,
+        };
+        Ok((wal, transactions))
+    }
+
+    // Replace the log at `path` with exactly `transactions`, via a temporary
+    // file so a crash midway leaves the old log in place
+    pub fn rewrite(path: &Path, transactions: &[Transaction]) -> io::Result<Wal> {
+        let temporary = path.with_extension("compact");
+        let mut wal = Wal {
+            file: BufWriter::new(File::create(&temporary)?),
+            unsynced: 0,
+        };
+        for transaction in transactions {
+            wal.write_record(transaction)?;
+        }
+        wal.file.flush()?;
+        wal.file.get_ref().sync_all()?;
+        fs::rename(&temporary, path)?;
+
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Wal {
+            file: BufWriter::new(file),
+            unsynced: 0,
+        })
+    }
+
+    pub fn append(&mut self, transaction: &Transaction) -> io::Result<()> {
+        self.write_record(transaction)?;
+        self.unsynced += 1;
+        if self.unsynced >= SYNC_BATCH {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // Flush buffered records and wait for them to reach the disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced == 0 {
+            return Ok(());
+        }
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.unsynced = 0;
+        Ok(())
+    }
+
+    fn write_record(&mut self, transaction: &Transaction) -> io::Result<()> {
+        let body = serde_json::to_vec(transaction)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.file.write_all(&(body.len() as u32).to_be_bytes())?;
+        self.file.write_all(&crc32(&body).to_be_bytes())?;
+        self.file.write_all(&body)
+    }
+}
+
+// Decode records up to the first damaged one, returning them with the length of
+// the intact prefix
+fn decode_records(contents: &[u8]) -> (Vec<Transaction>, usize) {
+    let mut transactions = Vec::new();
+    let mut offset = 0;
+    while contents.len() - offset >= HEADER_LEN {
+        let header = &contents[offset..offset + HEADER_LEN];
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let crc = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let start = offset + HEADER_LEN;
+        if len > MAX_FRAME_LEN || contents.len() - start < len {
+            break;
+        }
+
+        let body = &contents[start..start + len];
+        if crc32(body) != crc {
+            break;
+        }
+        let Ok(transaction) = serde_json::from_slice(body) else {
+            break;
+        };
+        transactions.push(transaction);
+        offset = start + len;
+    }
+    (transactions, offset)
+}
+
+// CRC-32 (IEEE), computed bitwise; records are small enough not to need a table
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn transaction(timestamp: u64) -> Transaction {
+        Transaction {
+            from: "node1".to_string(),
+            to: "node2".to_string(),
+            amount: 5,
+            timestamp,
+            signature: format!("sig{}", timestamp),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("p2p-wal-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn write_log(path: &Path, count: u64) {
+        let (mut wal, _) = Wal::open(path).unwrap();
+        for timestamp in 0..count {
+            wal.append(&transaction(timestamp)).unwrap();
+        }
+        wal.sync().unwrap();
+    }
+
+    #[test]
+    fn computes_the_standard_crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn replays_appended_records_in_order() {
+        let path = temp_path("replay");
+        write_log(&path, 3);
+
+        let (_, transactions) = Wal::open(&path).unwrap();
+        assert_eq!(
+            transactions,
+            [transaction(0), transaction(1), transaction(2)]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncates_a_partial_trailing_record() {
+        let path = temp_path("partial");
+        write_log(&path, 2);
+        let intact = fs::metadata(&path).unwrap().len();
+
+        // A crash halfway through writing a third record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 40, 1, 2]).unwrap();
+        drop(file);
+
+        let (mut wal, transactions) = Wal::open(&path).unwrap();
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(fs::metadata(&path).unwrap().len(), intact);
+
+        wal.append(&transaction(2)).unwrap();
+        wal.sync().unwrap();
+        let (_, transactions) = Wal::open(&path).unwrap();
+        assert_eq!(transactions.len(), 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stops_at_a_record_with_a_bad_checksum() {
+        let path = temp_path("checksum");
+        write_log(&path, 2);
+
+        // Flip a byte in the second record's body
+        let mut contents = fs::read(&path).unwrap();
+        let last = contents.len() - 2;
+        contents[last] ^= 0xFF;
+        fs::write(&path, contents).unwrap();
+
+        let (_, transactions) = Wal::open(&path).unwrap();
+        assert_eq!(transactions, [transaction(0)]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rewrites_the_log_from_scratch() {
+        let path = temp_path("rewrite");
+        write_log(&path, 5);
+
+        let mut wal = Wal::rewrite(&path, &[transaction(7)]).unwrap();
+        wal.append(&transaction(8)).unwrap();
+        wal.sync().unwrap();
+
+        let (_, transactions) = Wal::open(&path).unwrap();
+        assert_eq!(transactions, [transaction(7), transaction(8)]);
+        fs::remove_file(&path).unwrap();
+    }
+}
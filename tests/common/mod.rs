@@ -0,0 +1,73 @@
+// Fixtures shared by the integration tests; each test file uses only some
+#![allow(dead_code)]
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use ed25519_dalek::SigningKey;
+use p2p_solana_network_simulation::ledger::Ledger;
+use p2p_solana_network_simulation::net::{Client, NodeOptions};
+use p2p_solana_network_simulation::protocol::{Message, Transaction, encode_pubkey};
+use p2p_solana_network_simulation::state::NodeState;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout};
+
+// The account every test transaction is sent from
+pub fn sender() -> SigningKey {
+    SigningKey::from_bytes(&[1; 32])
+}
+
+// The account every test transaction is sent to
+pub fn recipient() -> String {
+    encode_pubkey(&SigningKey::from_bytes(&[2; 32]).verifying_key())
+}
+
+// A signed transfer from the sender to the recipient
+pub fn transfer(amount: u64, timestamp: u64) -> Transaction {
+    Transaction::new_signed(&sender(), recipient(), amount, timestamp)
+}
+
+// Node options whose genesis gives the sender `balance`
+pub fn funded_options(balance: u64) -> NodeOptions {
+    NodeOptions {
+        genesis: Ledger::new([(encode_pubkey(&sender().verifying_key()), balance)]),
+        ..NodeOptions::default()
+    }
+}
+
+// Wait until the node has at least `peers` peers registered
+pub async fn wait_until_connected(state: &Arc<Mutex<NodeState>>, peers: usize) {
+    timeout(Duration::from_secs(5), async {
+        while state.lock().await.peer_count() < peers {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("never reached {} peers", peers));
+}
+
+// Wait until the node holds at least `count` transactions
+pub async fn wait_for_transactions(state: &Arc<Mutex<NodeState>>, count: usize) {
+    timeout(Duration::from_secs(10), async {
+        while state.lock().await.transaction_count() < count {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("never reached {} transactions", count));
+}
+
+// Send transactions to the node at `addr` as a client, in order, without
+// waiting for them to be handled
+pub async fn submit(addr: &str, transactions: impl IntoIterator<Item = Transaction>) {
+    let mut client = Client::connect(addr, None).await.unwrap();
+    for transaction in transactions {
+        client
+            .send(&Message::Transaction {
+                transaction,
+                hops: 0,
+            })
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+mod common;
+
+use std::time::Duration;
+
+use bytes::BytesMut;
+use p2p_solana_network_simulation::net::{Node, NodeOptions, write_message};
+use p2p_solana_network_simulation::protocol::{FrameCodec, Message, PROTOCOL_VERSION, PeerInfo};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+// Read frames until the node closes the connection
+async fn read_until_closed(socket: &mut TcpStream) -> Vec<Message> {
+    let codec = FrameCodec::default();
+    let mut buffer = BytesMut::new();
+    let mut messages = Vec::new();
+    loop {
+        while let Some(message) = codec.decode(&mut buffer).unwrap() {
+            messages.push(message);
+        }
+        match socket.read_buf(&mut buffer).await {
+            Ok(0) | Err(_) => return messages,
+            Ok(_) => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn both_sides_list_each_other_by_node_id() {
+    let a = Node::bind("127.0.0.1:0", NodeOptions::default())
+        .await
+        .unwrap();
+    let b = Node::bind("127.0.0.1:0", NodeOptions::default())
+        .await
+        .unwrap();
+    let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+    let (a_id, b_id) = (a.node_id().to_string(), b.node_id().to_string());
+    let (a_state, b_state) = (a.state(), b.state());
+
+    b.connect(a_addr.to_string());
+    tokio::spawn(a.run());
+    tokio::spawn(b.run());
+
+    common::wait_until_connected(&a_state, 1).await;
+    common::wait_until_connected(&b_state, 1).await;
+
+    // A learns B's dialable address from its Hello, not the ephemeral source port
+    let a_peers: Vec<_> = a_state.lock().await.peers().cloned().collect();
+    assert_eq!(
+        a_peers,
+        [PeerInfo {
+            node_id: b_id,
+            addr: b_addr.to_string(),
+            rtt_micros: None,
+        }]
+    );
+    let b_peers: Vec<_> = b_state.lock().await.peers().cloned().collect();
+    assert_eq!(
+        b_peers,
+        [PeerInfo {
+            node_id: a_id,
+            addr: a_addr.to_string(),
+            rtt_micros: None,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn refuses_other_protocol_versions() {
+    let node = Node::bind("127.0.0.1:0", NodeOptions::default())
+        .await
+        .unwrap();
+    let addr = node.local_addr().unwrap();
+    let state = node.state();
+    tokio::spawn(node.run());
+
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let hello = Message::Hello {
+        node_id: "future-node".to_string(),
+        protocol_version: PROTOCOL_VERSION + 1,
+        listen_port: 9000,
+    };
+    write_message(&mut socket, &hello).await.unwrap();
+
+    let messages = timeout(Duration::from_secs(5), read_until_closed(&mut socket))
+        .await
+        .expect("node kept the connection open");
+    assert!(matches!(messages[0], Message::Hello { .. }));
+    assert!(
+        matches!(&messages[1], Message::Reject { reason } if reason.contains("protocol version")),
+        "expected a Reject, got {:?}",
+        messages
+    );
+    assert_eq!(state.lock().await.peer_count(), 0);
+}
+
+#[tokio::test]
+async fn drops_connections_that_never_say_hello() {
+    let options = NodeOptions {
+        handshake_timeout: Duration::from_millis(100),
+        ..NodeOptions::default()
+    };
+    let node = Node::bind("127.0.0.1:0", options).await.unwrap();
+    let addr = node.local_addr().unwrap();
+    tokio::spawn(node.run());
+
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let messages = timeout(Duration::from_secs(5), read_until_closed(&mut socket))
+        .await
+        .expect("node kept a silent connection open");
+    assert_eq!(messages.len(), 1, "expected only the node's Hello");
+}
+
+#[tokio::test]
+async fn nodes_dialing_each_other_at_once_keep_one_connection() {
+    // Many pairs, to give the two handshakes every chance to interleave
+    let mut pairs = Vec::new();
+    for _ in 0..20 {
+        let a = Node::bind("127.0.0.1:0", NodeOptions::default())
+            .await
+            .unwrap();
+        let b = Node::bind("127.0.0.1:0", NodeOptions::default())
+            .await
+            .unwrap();
+        let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+        let (a_dialer, b_dialer) = (a.dialer(), b.dialer());
+        pairs.push((a.state(), b.state()));
+        tokio::spawn(a.run());
+        tokio::spawn(b.run());
+        a_dialer.connect(b_addr.to_string());
+        b_dialer.connect(a_addr.to_string());
+    }
+
+    for (a_state, b_state) in &pairs {
+        common::wait_until_connected(a_state, 1).await;
+        common::wait_until_connected(b_state, 1).await;
+    }
+    // Past the first redial, the pairs should still be connected, once each
+    sleep(Duration::from_millis(1500)).await;
+    for (i, (a_state, b_state)) in pairs.iter().enumerate() {
+        assert_eq!(a_state.lock().await.peer_count(), 1, "pair {}", i);
+        assert_eq!(b_state.lock().await.peer_count(), 1, "pair {}", i);
+    }
+}
+
+#[tokio::test]
+async fn a_hello_with_a_connected_peers_id_does_not_take_its_place() {
+    let options = || NodeOptions {
+        handshake_timeout: Duration::from_millis(300),
+        ..NodeOptions::default()
+    };
+    let a = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    let b = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+    let b_id = b.node_id().to_string();
+    let (a_state, b_state) = (a.state(), b.state());
+
+    b.connect(a_addr.to_string());
+    tokio::spawn(a.run());
+    tokio::spawn(b.run());
+    common::wait_until_connected(&a_state, 1).await;
+    common::wait_until_connected(&b_state, 1).await;
+
+    // Anyone can claim B's node id, whichever side B's connection was dialed from
+    let mut impostor = TcpStream::connect(a_addr).await.unwrap();
+    let hello = Message::Hello {
+        node_id: b_id.clone(),
+        protocol_version: PROTOCOL_VERSION,
+        listen_port: 9,
+    };
+    write_message(&mut impostor, &hello).await.unwrap();
+
+    let messages = timeout(Duration::from_secs(5), read_until_closed(&mut impostor))
+        .await
+        .expect("node kept the impostor connected");
+    assert!(
+        matches!(messages.last(), Some(Message::Reject { .. })),
+        "expected a Reject, got {:?}",
+        messages
+    );
+    let a_peers: Vec<_> = a_state.lock().await.peers().cloned().collect();
+    assert_eq!(a_peers.len(), 1);
+    assert_eq!(a_peers[0].node_id, b_id);
+    assert_eq!(a_peers[0].addr, b_addr.to_string());
+    assert_eq!(b_state.lock().await.peer_count(), 1);
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{funded_options, submit, transfer};
+use p2p_solana_network_simulation::metrics::Metrics;
+use p2p_solana_network_simulation::net::{Node, NodeOptions};
+use p2p_solana_network_simulation::protocol::Transaction;
+use p2p_solana_network_simulation::state::NodeState;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout};
+
+struct Started {
+    addr: String,
+    state: Arc<Mutex<NodeState>>,
+    metrics: Arc<Metrics>,
+}
+
+// Start nodes where node i dials each node listed in links[i]
+async fn start_nodes(max_hops: u8, links: &[&[usize]]) -> Vec<Started> {
+    let mut nodes = Vec::new();
+    for _ in links {
+        let options = NodeOptions {
+            max_hops,
+            ..funded_options(100)
+        };
+        nodes.push(Node::bind("127.0.0.1:0", options).await.unwrap());
+    }
+    let addrs: Vec<String> = nodes
+        .iter()
+        .map(|node| node.local_addr().unwrap().to_string())
+        .collect();
+    for (node, peers) in nodes.iter().zip(links) {
+        for &peer in *peers {
+            node.connect(addrs[peer].clone());
+        }
+    }
+
+    let mut started = Vec::new();
+    for (node, addr) in nodes.into_iter().zip(addrs) {
+        started.push(Started {
+            addr,
+            state: node.state(),
+            metrics: node.metrics(),
+        });
+        tokio::spawn(node.run());
+    }
+    for (i, node) in started.iter().enumerate() {
+        let expected = links[i].len() + links.iter().filter(|peers| peers.contains(&i)).count();
+        common::wait_until_connected(&node.state, expected).await;
+    }
+    started
+}
+
+async fn submit_one(addr: &str) -> Transaction {
+    let transaction = transfer(10, 1);
+    submit(addr, [transaction.clone()]).await;
+    transaction
+}
+
+#[tokio::test]
+async fn cycle_delivers_a_transaction_once_to_each_node() {
+    let nodes = start_nodes(4, &[&[1], &[2], &[0]]).await;
+    let transaction = submit_one(&nodes[0].addr).await;
+
+    // Each node relays it exactly once, however the copies race round the cycle
+    timeout(Duration::from_secs(5), async {
+        while nodes
+            .iter()
+            .map(|node| node.metrics.snapshot().fanout.relays)
+            .sum::<u64>()
+            < 3
+        {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the transaction never went round");
+    sleep(Duration::from_millis(200)).await;
+
+    for node in &nodes {
+        let state = node.state.lock().await;
+        assert_eq!(state.transaction_count(), 1);
+        assert!(state.transaction(&transaction.signature).is_some());
+        assert_eq!(node.metrics.snapshot().fanout.relays, 1);
+    }
+}
+
+#[tokio::test]
+async fn stops_relaying_at_the_hop_limit() {
+    // A line: 0 - 1 - 2, with transactions forwarded at most once
+    let nodes = start_nodes(1, &[&[1], &[2], &[]]).await;
+    let transaction = submit_one(&nodes[0].addr).await;
+
+    timeout(Duration::from_secs(5), async {
+        while nodes[1]
+            .state
+            .lock()
+            .await
+            .transaction(&transaction.signature)
+            .is_none()
+        {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the transaction never reached node 1");
+    sleep(Duration::from_millis(200)).await;
+
+    assert_eq!(nodes[2].state.lock().await.transaction_count(), 0);
+    assert_eq!(nodes[1].metrics.snapshot().fanout.relays, 0);
+}
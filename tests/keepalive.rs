@@ -0,0 +1,173 @@
+mod common;
+
+use std::time::Duration;
+
+use bytes::BytesMut;
+use p2p_solana_network_simulation::net::{Node, NodeOptions, write_message};
+use p2p_solana_network_simulation::protocol::{FrameCodec, Message, PROTOCOL_VERSION};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+
+const KEEPALIVE: Duration = Duration::from_millis(100);
+
+fn options() -> NodeOptions {
+    NodeOptions {
+        keepalive: KEEPALIVE,
+        ..NodeOptions::default()
+    }
+}
+
+fn hello(node_id: &str) -> Message {
+    Message::Hello {
+        node_id: node_id.to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        listen_port: 9000,
+    }
+}
+
+async fn read_one(socket: &mut TcpStream, buffer: &mut BytesMut) -> Option<Message> {
+    let codec = FrameCodec::default();
+    loop {
+        if let Some(message) = codec.decode(buffer).unwrap() {
+            return Some(message);
+        }
+        if socket.read_buf(buffer).await.unwrap() == 0 {
+            return None;
+        }
+    }
+}
+
+#[tokio::test]
+async fn records_round_trips_between_nodes() {
+    let a = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    let b = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    let a_addr = a.local_addr().unwrap();
+    let b_state = b.state();
+
+    b.connect(a_addr.to_string());
+    tokio::spawn(a.run());
+    tokio::spawn(b.run());
+
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let rtt = b_state
+                .lock()
+                .await
+                .peers()
+                .next()
+                .and_then(|p| p.rtt_micros);
+            if rtt.is_some() {
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("no round trip was recorded");
+}
+
+#[tokio::test]
+async fn drops_peers_that_stop_answering_pings() {
+    let node = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    let addr = node.local_addr().unwrap();
+    let state = node.state();
+    tokio::spawn(node.run());
+
+    // A peer that completes the handshake and then goes silent
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    write_message(&mut socket, &hello("silent-peer"))
+        .await
+        .unwrap();
+    let mut buffer = BytesMut::new();
+    assert!(matches!(
+        read_one(&mut socket, &mut buffer).await,
+        Some(Message::Hello { .. })
+    ));
+
+    common::wait_until_connected(&state, 1).await;
+
+    // Keep reading so only the missing pongs, not a full buffer, end the connection
+    let started = tokio::time::Instant::now();
+    let mut pings = 0;
+    while let Some(message) = read_one(&mut socket, &mut buffer).await {
+        match message {
+            Message::Ping { .. } => pings += 1,
+            Message::SyncRequest { .. } => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+    assert_eq!(pings, 3);
+    assert!(started.elapsed() < KEEPALIVE * 6);
+    assert_eq!(state.lock().await.peer_count(), 0);
+}
+
+#[tokio::test]
+async fn redials_peers_whose_connection_drops() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let node = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    node.connect(listener.local_addr().unwrap().to_string());
+    tokio::spawn(node.run());
+
+    // Complete the handshake, then hang up
+    let (mut socket, _) = listener.accept().await.unwrap();
+    let mut buffer = BytesMut::new();
+    assert!(matches!(
+        read_one(&mut socket, &mut buffer).await,
+        Some(Message::Hello { .. })
+    ));
+    write_message(&mut socket, &hello("flaky-peer"))
+        .await
+        .unwrap();
+    drop(socket);
+
+    let (mut socket, _) = timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .expect("node never redialed")
+        .unwrap();
+    let mut buffer = BytesMut::new();
+    assert!(matches!(
+        read_one(&mut socket, &mut buffer).await,
+        Some(Message::Hello { .. })
+    ));
+}
+
+#[tokio::test]
+async fn keeps_redialing_a_peer_that_refuses_while_it_notices_the_drop() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let node = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    node.connect(listener.local_addr().unwrap().to_string());
+    tokio::spawn(node.run());
+
+    // Complete the handshake, then hang up
+    let (mut socket, _) = listener.accept().await.unwrap();
+    let mut buffer = BytesMut::new();
+    read_one(&mut socket, &mut buffer).await;
+    write_message(&mut socket, &hello("stale-peer"))
+        .await
+        .unwrap();
+    drop(socket);
+
+    // Refuse the first redial, as a peer still listing the old connection would
+    let (mut socket, _) = timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .expect("node never redialed")
+        .unwrap();
+    let mut buffer = BytesMut::new();
+    read_one(&mut socket, &mut buffer).await;
+    let reject = Message::Reject {
+        reason: "already connected or peer limit reached".to_string(),
+    };
+    write_message(&mut socket, &reject).await.unwrap();
+    drop(socket);
+
+    let (mut socket, _) = timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .expect("node gave up after being refused")
+        .unwrap();
+    let mut buffer = BytesMut::new();
+    assert!(matches!(
+        read_one(&mut socket, &mut buffer).await,
+        Some(Message::Hello { .. })
+    ));
+}
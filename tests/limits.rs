@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use bytes::BytesMut;
+use p2p_solana_network_simulation::net::{Client, Node, NodeOptions};
+use p2p_solana_network_simulation::protocol::{FrameCodec, Message};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout};
+
+// Small enough for the usual limit of 1024 open files per process
+const FLOOD: usize = 512;
+const MAX_CONNECTIONS: usize = 32;
+
+async fn start_node(max_connections: usize, max_connections_per_ip: usize) -> String {
+    let options = NodeOptions {
+        max_connections,
+        max_connections_per_ip,
+        // Flood connections never say Hello; keep them open for the whole test
+        handshake_timeout: Duration::from_secs(60),
+        ..NodeOptions::default()
+    };
+    let node = Node::bind("127.0.0.1:0", options).await.unwrap();
+    let addr = node.local_addr().unwrap().to_string();
+    tokio::spawn(node.run());
+    addr
+}
+
+// The first frame the node sends on a raw connection
+async fn first_message(socket: &mut TcpStream) -> Message {
+    let codec = FrameCodec::default();
+    let mut buffer = BytesMut::new();
+    loop {
+        if let Some(message) = codec.decode(&mut buffer).unwrap() {
+            return message;
+        }
+        let read = timeout(Duration::from_secs(10), socket.read_buf(&mut buffer))
+            .await
+            .expect("node never answered")
+            .unwrap();
+        assert!(read > 0, "node closed the connection without a frame");
+    }
+}
+
+async fn status(client: &mut Client) -> Message {
+    timeout(
+        Duration::from_secs(2),
+        client.request(|request_id| Message::GetStatus { request_id }),
+    )
+    .await
+    .expect("node stopped answering")
+    .unwrap()
+}
+
+// Open `connections` raw connections to a node accepting `max_connections`
+// and check only the ones that fit are greeted
+async fn flood(connections: usize, max_connections: usize) {
+    let addr = start_node(max_connections, connections).await;
+    let mut client = Client::connect(&addr, None).await.unwrap();
+
+    let mut flood = Vec::with_capacity(connections);
+    for _ in 0..connections {
+        flood.push(TcpStream::connect(&addr).await.unwrap());
+    }
+
+    // The client already holds one slot
+    let mut greeted = 0;
+    for socket in &mut flood {
+        match first_message(socket).await {
+            Message::Hello { .. } => greeted += 1,
+            Message::Busy { .. } => {}
+            other => panic!("unexpected first message {:?}", other),
+        }
+    }
+    assert_eq!(greeted, max_connections - 1);
+    assert!(matches!(status(&mut client).await, Message::Status { .. }));
+
+    // Slots come back once the flood disconnects
+    drop(flood);
+    timeout(Duration::from_secs(5), async {
+        loop {
+            match Client::connect(&addr, None).await {
+                Ok(client) => return client,
+                Err(_) => sleep(Duration::from_millis(10)).await,
+            }
+        }
+    })
+    .await
+    .expect("slots were never released");
+}
+
+#[tokio::test]
+async fn flood_of_connections_is_turned_away_within_the_limit() {
+    flood(FLOOD, MAX_CONNECTIONS).await;
+}
+
+// Needs a limit of well over 10k open files: `ulimit -n 20000`
+#[tokio::test]
+#[ignore = "opens 10k sockets"]
+async fn flood_of_10k_connections_is_turned_away_within_the_limit() {
+    flood(10_000, 64).await;
+}
+
+#[tokio::test]
+async fn caps_connections_from_one_address() {
+    let addr = start_node(MAX_CONNECTIONS, 2).await;
+    let _first = Client::connect(&addr, None).await.unwrap();
+    let _second = Client::connect(&addr, None).await.unwrap();
+
+    let mut third = TcpStream::connect(&addr).await.unwrap();
+    assert_eq!(
+        first_message(&mut third).await,
+        Message::Busy {
+            reason: "too many connections from your address".to_string(),
+        }
+    );
+}
@@ -0,0 +1,154 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{funded_options, recipient, sender, transfer};
+use p2p_solana_network_simulation::net::{Client, Node};
+use p2p_solana_network_simulation::protocol::{Message, Transaction, encode_pubkey};
+use p2p_solana_network_simulation::{query_status, send_transaction};
+use tokio::time::{sleep, timeout};
+
+async fn start_node() -> String {
+    let node = Node::bind("127.0.0.1:0", funded_options(100))
+        .await
+        .unwrap();
+    let addr = node.local_addr().unwrap().to_string();
+    tokio::spawn(node.run());
+    addr
+}
+
+#[tokio::test]
+async fn answers_each_query_with_its_request_id() {
+    let addr = start_node().await;
+    let transaction = transfer(30, 1);
+    assert!(
+        send_transaction(&addr, transaction.clone(), None)
+            .await
+            .unwrap()
+    );
+
+    let mut client = Client::connect(&addr, None).await.unwrap();
+    let id = transaction.signature.clone();
+    assert_eq!(
+        client
+            .request(|request_id| Message::GetTx { request_id, id })
+            .await
+            .unwrap(),
+        Message::Tx {
+            request_id: 0,
+            transaction,
+        }
+    );
+    assert_eq!(
+        client
+            .request(|request_id| Message::GetTx {
+                request_id,
+                id: "unknown".to_string(),
+            })
+            .await
+            .unwrap(),
+        Message::NotFound { request_id: 1 }
+    );
+    assert_eq!(
+        client
+            .request(|request_id| Message::GetBalance {
+                request_id,
+                account: recipient(),
+            })
+            .await
+            .unwrap(),
+        Message::Balance {
+            request_id: 2,
+            account: recipient(),
+            balance: 30,
+        }
+    );
+    assert_eq!(
+        client
+            .request(|request_id| Message::GetBalance {
+                request_id,
+                account: "nobody".to_string(),
+            })
+            .await
+            .unwrap(),
+        Message::NotFound { request_id: 3 }
+    );
+    assert_eq!(
+        client
+            .request(|request_id| Message::GetPeers { request_id })
+            .await
+            .unwrap(),
+        Message::Peers {
+            request_id: 4,
+            peers: Vec::new(),
+        }
+    );
+}
+
+#[tokio::test]
+async fn reports_rejected_transactions_and_status() {
+    let addr = start_node().await;
+
+    // More than the sender holds
+    let overdraft = transfer(101, 1);
+    assert!(!send_transaction(&addr, overdraft, None).await.unwrap());
+
+    let transaction = transfer(40, 2);
+    assert!(send_transaction(&addr, transaction, None).await.unwrap());
+
+    let status = query_status(&addr, None, Some(&recipient())).await.unwrap();
+    assert_eq!(status.transaction_count, 1);
+    assert!(status.peers.is_empty());
+    assert_eq!(status.balance, Some(40));
+
+    let status = query_status(&addr, None, Some("nobody")).await.unwrap();
+    assert_eq!(status.balance, None);
+}
+
+#[tokio::test]
+async fn refuses_free_transfers_and_non_key_recipients() {
+    let addr = start_node().await;
+
+    // Neither costs the sender anything, so neither may cost the node anything
+    let unfunded = ed25519_dalek::SigningKey::from_bytes(&[9; 32]);
+    let free = Transaction::new_signed(&unfunded, recipient(), 0, 1);
+    assert!(!send_transaction(&addr, free, None).await.unwrap());
+    let misaddressed = Transaction::new_signed(&sender(), "x".repeat(1000), 5, 2);
+    assert!(!send_transaction(&addr, misaddressed, None).await.unwrap());
+
+    let status = query_status(&addr, None, None).await.unwrap();
+    assert_eq!(status.transaction_count, 0);
+    assert_eq!(status.stats.total.rejected_transactions, 2);
+    let unfunded = encode_pubkey(&unfunded.verifying_key());
+    let status = query_status(&addr, None, Some(&unfunded)).await.unwrap();
+    assert_eq!(status.balance, None);
+}
+
+#[tokio::test]
+async fn reports_traffic_stats() {
+    let addr = start_node().await;
+    let overdraft = transfer(101, 1);
+    assert!(!send_transaction(&addr, overdraft, None).await.unwrap());
+    let transaction = transfer(40, 2);
+    assert!(send_transaction(&addr, transaction, None).await.unwrap());
+
+    // The sending connections may still be closing
+    let stats = timeout(Duration::from_secs(5), async {
+        loop {
+            let stats = query_status(&addr, None, None).await.unwrap().stats;
+            if stats.fanout.relays == 1 {
+                return stats;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the relay never finished");
+    assert_eq!(stats.total.rejected_transactions, 1);
+    assert_eq!(stats.total.invalid_frames, 0);
+    // Hello, transaction and get_tx from each sender, plus this query's frames
+    assert!(stats.total.frames_in >= 6, "{:?}", stats.total);
+    assert!(stats.total.bytes_in > stats.total.frames_in * 4);
+    assert!(stats.active_connections >= 1);
+    assert!(stats.peers.is_empty());
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+use common::{funded_options, submit, transfer, wait_for_transactions, wait_until_connected};
+use p2p_solana_network_simulation::net::{Node, NodeOptions};
+use tokio::runtime::{Builder, Runtime};
+
+const NODES: usize = 16;
+const TRANSACTIONS: u64 = 500;
+
+// Time a 16-node ring from startup until every node holds every transaction
+// sent to node 0
+async fn ring_convergence() -> (Duration, Duration) {
+    let started = Instant::now();
+    let mut nodes = Vec::new();
+    for _ in 0..NODES {
+        let options = NodeOptions {
+            // Far enough to go halfway round the ring either way
+            max_hops: NODES as u8,
+            ..funded_options(TRANSACTIONS)
+        };
+        nodes.push(Node::bind("127.0.0.1:0", options).await.unwrap());
+    }
+    let addrs: Vec<String> = nodes
+        .iter()
+        .map(|node| node.local_addr().unwrap().to_string())
+        .collect();
+    for (i, node) in nodes.iter().enumerate() {
+        node.connect(addrs[(i + 1) % NODES].clone());
+    }
+    let states: Vec<_> = nodes.iter().map(|node| node.state()).collect();
+    for node in nodes {
+        tokio::spawn(node.run());
+    }
+    for state in &states {
+        wait_until_connected(state, 2).await;
+    }
+    let connected = started.elapsed();
+
+    let sent = Instant::now();
+    submit(
+        &addrs[0],
+        (0..TRANSACTIONS).map(|timestamp| transfer(1, timestamp)),
+    )
+    .await;
+    for state in &states {
+        wait_for_transactions(state, TRANSACTIONS as usize).await;
+    }
+    (connected, sent.elapsed())
+}
+
+fn time(name: &str, runtime: Runtime) {
+    let (connected, converged) = runtime.block_on(ring_convergence());
+    println!(
+        "{}: ring connected in {:?}, {} transactions converged in {:?}",
+        name, connected, TRANSACTIONS, converged
+    );
+}
+
+// Compare the executors that `--single-threaded` and `--workers` pick between,
+// with multi-thread runtimes of 4 workers and one per CPU. Run with
+// `cargo test --release --test runtime -- --ignored --nocapture`.
+#[test]
+#[ignore = "benchmark"]
+fn compare_current_thread_with_multi_thread() {
+    let current = Builder::new_current_thread().enable_all().build().unwrap();
+    time("current-thread", current);
+
+    let cpus = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let mut worker_counts = vec![4, cpus];
+    worker_counts.sort();
+    worker_counts.dedup();
+    for workers in worker_counts {
+        let multi = Builder::new_multi_thread()
+            .worker_threads(workers)
+            .enable_all()
+            .build()
+            .unwrap();
+        time(&format!("multi-thread with {} workers", workers), multi);
+    }
+}
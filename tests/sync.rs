@@ -0,0 +1,170 @@
+mod common;
+
+use std::fs;
+use std::time::Duration;
+
+use common::{
+    funded_options, recipient, sender, submit, transfer, wait_for_transactions,
+    wait_until_connected,
+};
+use p2p_solana_network_simulation::net::{Node, NodeOptions};
+use p2p_solana_network_simulation::protocol::{Transaction, encode_pubkey};
+use p2p_solana_network_simulation::wal::Wal;
+use tokio::time::sleep;
+
+const TRANSACTIONS: u64 = 1000;
+
+fn options() -> NodeOptions {
+    funded_options(TRANSACTIONS)
+}
+
+#[tokio::test]
+async fn late_joiner_catches_up_on_earlier_transactions() {
+    // A short relay queue, so the burst below overruns B's
+    let a_options = NodeOptions {
+        relay_queue_len: 16,
+        ..options()
+    };
+    let a = Node::bind("127.0.0.1:0", a_options).await.unwrap();
+    let b = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+    let (a_state, b_state) = (a.state(), b.state());
+
+    b.connect(a_addr.to_string());
+    tokio::spawn(a.run());
+    tokio::spawn(b.run());
+    wait_until_connected(&a_state, 1).await;
+    wait_until_connected(&b_state, 1).await;
+
+    // One burst; whatever A drops for B, B resyncs
+    let burst = (0..TRANSACTIONS).map(|timestamp| transfer(1, timestamp));
+    submit(&a_addr.to_string(), burst).await;
+    wait_for_transactions(&b_state, TRANSACTIONS as usize).await;
+    assert_eq!(b_state.lock().await.balance(&recipient()), TRANSACTIONS);
+
+    let c = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    let c_state = c.state();
+    c.connect(b_addr.to_string());
+    tokio::spawn(c.run());
+
+    wait_for_transactions(&c_state, TRANSACTIONS as usize).await;
+    let c_state = c_state.lock().await;
+    assert_eq!(c_state.transaction_count() as u64, TRANSACTIONS);
+    assert_eq!(c_state.balance(&recipient()), TRANSACTIONS);
+    assert_eq!(
+        c_state.ledger(),
+        b_state.lock().await.ledger(),
+        "C's ledger differs from B's"
+    );
+}
+
+#[tokio::test]
+async fn peer_that_falls_behind_resyncs_what_it_missed() {
+    const BURST: usize = 200;
+    // A can only queue one relay for B, so a burst overruns it
+    let a = Node::bind(
+        "127.0.0.1:0",
+        NodeOptions {
+            relay_queue_len: 1,
+            ..funded_options(BURST as u64)
+        },
+    )
+    .await
+    .unwrap();
+    let b = Node::bind("127.0.0.1:0", funded_options(BURST as u64))
+        .await
+        .unwrap();
+    let a_addr = a.local_addr().unwrap();
+    let (a_state, b_state) = (a.state(), b.state());
+
+    b.connect(a_addr.to_string());
+    tokio::spawn(a.run());
+    tokio::spawn(b.run());
+    wait_until_connected(&a_state, 1).await;
+    wait_until_connected(&b_state, 1).await;
+
+    let burst = (0..BURST as u64).map(|timestamp| transfer(1, timestamp));
+    submit(&a_addr.to_string(), burst).await;
+
+    wait_for_transactions(&b_state, BURST).await;
+    assert_eq!(b_state.lock().await.balance(&recipient()), BURST as u64);
+    // Only B's connection is relayed to, and it can't miss more than were sent
+    let dropped = a_state.lock().await.dropped_relays();
+    assert!(dropped > 0, "the burst never overran B's queue");
+    assert!(dropped < BURST as u64, "{} relays dropped", dropped);
+}
+
+#[tokio::test]
+async fn nodes_holding_future_transactions_still_sync_older_ones() {
+    let a = Node::bind("127.0.0.1:0", funded_options(11)).await.unwrap();
+    let b = Node::bind("127.0.0.1:0", funded_options(11)).await.unwrap();
+    let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+    let (a_state, b_state) = (a.state(), b.state());
+    let b_dialer = b.dialer();
+    tokio::spawn(a.run());
+    tokio::spawn(b.run());
+
+    // A holds a transaction dated far ahead of everything B holds
+    submit(&a_addr.to_string(), [transfer(1, u64::MAX / 2)]).await;
+    submit(
+        &b_addr.to_string(),
+        (0..10).map(|timestamp| transfer(1, timestamp)),
+    )
+    .await;
+    wait_for_transactions(&a_state, 1).await;
+    wait_for_transactions(&b_state, 10).await;
+
+    b_dialer.connect(a_addr.to_string());
+    wait_for_transactions(&a_state, 11).await;
+    wait_for_transactions(&b_state, 11).await;
+    assert_eq!(
+        a_state.lock().await.ledger(),
+        b_state.lock().await.ledger(),
+        "A's ledger differs from B's"
+    );
+}
+
+#[tokio::test]
+async fn large_transactions_sync_in_pages_that_fit_a_frame() {
+    const LARGE: u64 = 300;
+    // Transactions too large to relay, which only a replayed log can hold.
+    // A page of MAX_SYNC_BATCH of them is larger than a frame.
+    let wal = std::env::temp_dir().join(format!("p2p-node-wal-sync-{}", std::process::id()));
+    let _ = fs::remove_file(&wal);
+    let (mut log, _) = Wal::open(&wal).unwrap();
+    for timestamp in 0..LARGE {
+        let large = Transaction {
+            from: encode_pubkey(&sender().verifying_key()),
+            to: "x".repeat(600),
+            amount: 1,
+            timestamp,
+            signature: format!("large{}", timestamp),
+        };
+        log.append(&large).unwrap();
+    }
+    log.sync().unwrap();
+    drop(log);
+
+    let a_options = NodeOptions {
+        wal: Some(wal.clone()),
+        ..funded_options(LARGE + 5)
+    };
+    let a = Node::bind("127.0.0.1:0", a_options).await.unwrap();
+    let a_addr = a.local_addr().unwrap();
+    let a_state = a.state();
+    tokio::spawn(a.run());
+    let later = (LARGE..LARGE + 5).map(|timestamp| transfer(1, timestamp));
+    submit(&a_addr.to_string(), later).await;
+    wait_for_transactions(&a_state, LARGE as usize + 5).await;
+
+    // B drops the large ones, and gets the rest only if every page arrives
+    let b = Node::bind("127.0.0.1:0", funded_options(5)).await.unwrap();
+    let b_state = b.state();
+    b.connect(a_addr.to_string());
+    tokio::spawn(b.run());
+    wait_for_transactions(&b_state, 5).await;
+    sleep(Duration::from_millis(200)).await;
+    assert_eq!(b_state.lock().await.balance(&recipient()), 5);
+    assert_eq!(a_state.lock().await.peer_count(), 1, "A dropped B");
+    let _ = fs::remove_file(&wal);
+}
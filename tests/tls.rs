@@ -0,0 +1,258 @@
+mod common;
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use common::{funded_options, transfer, wait_for_transactions, wait_until_connected};
+use p2p_solana_network_simulation::net::{Node, NodeOptions, write_message};
+use p2p_solana_network_simulation::protocol::{FrameCodec, Message, PROTOCOL_VERSION};
+use p2p_solana_network_simulation::tls::{TlsContext, TlsOptions};
+use p2p_solana_network_simulation::{query_status, send_transaction};
+use rcgen::{CertificateParams, ExtendedKeyUsagePurpose, Issuer, KeyPair};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, crypto::ring};
+
+fn tls_options(name: &str) -> TlsOptions {
+    let dir = std::env::temp_dir().join(format!("p2p-tls-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    TlsOptions {
+        ca: dir.join("ca.pem"),
+        cert: None,
+        key: None,
+    }
+}
+
+// Node-side TLS for a node listening on 127.0.0.1, creating the CA if needed
+fn node_tls(tls: &TlsOptions) -> TlsContext {
+    TlsContext::for_node(tls, "test-node", IpAddr::V4(Ipv4Addr::LOCALHOST)).unwrap()
+}
+
+fn with_tls(tls: Option<&TlsOptions>, options: NodeOptions) -> NodeOptions {
+    NodeOptions {
+        tls: tls.map(node_tls),
+        ..options
+    }
+}
+
+async fn start_node(tls: Option<&TlsOptions>) -> String {
+    let node = Node::bind("127.0.0.1:0", with_tls(tls, NodeOptions::default()))
+        .await
+        .unwrap();
+    let addr = node.local_addr().unwrap().to_string();
+    tokio::spawn(node.run());
+    addr
+}
+
+// Whether a node handled a Hello sent over an established TLS stream
+async fn answers_hello<S>(mut stream: S) -> bool
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let hello = Message::Hello {
+        node_id: "tls-test".to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        listen_port: 0,
+    };
+    if write_message(&mut stream, &hello).await.is_err() {
+        return false;
+    }
+    let codec = FrameCodec::default();
+    let mut buffer = BytesMut::new();
+    let read = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Ok(Some(message)) = codec.decode(&mut buffer) {
+                return Some(message);
+            }
+            match stream.read_buf(&mut buffer).await {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {}
+            }
+        }
+    });
+    matches!(
+        read.await.expect("node neither answered nor hung up"),
+        Some(Message::Hello { .. })
+    )
+}
+
+fn cleanup(tls: TlsOptions) {
+    fs::remove_dir_all(tls.ca.parent().unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn queries_a_node_over_tls() {
+    let tls = tls_options("query");
+    let addr = start_node(Some(&tls)).await;
+
+    let status = query_status(&addr, Some(&tls), None).await.unwrap();
+    assert_eq!(status.transaction_count, 0);
+    cleanup(tls);
+}
+
+#[tokio::test]
+async fn clients_need_an_existing_ca() {
+    let tls = tls_options("missing-ca");
+    let addr = start_node(None).await;
+
+    let error = query_status(&addr, Some(&tls), None).await.err().unwrap();
+    assert!(
+        error.to_string().contains("not found"),
+        "unhelpful error: {}",
+        error
+    );
+    assert!(!tls.ca.exists(), "a client generated a CA");
+    assert!(!tls.ca.with_extension("key").exists());
+    cleanup(tls);
+}
+
+#[tokio::test]
+async fn issued_certificates_name_the_listen_address() {
+    let tls = tls_options("listen-name");
+    let listen = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+    let options = NodeOptions {
+        tls: Some(TlsContext::for_node(&tls, "test-node", listen).unwrap()),
+        ..NodeOptions::default()
+    };
+    let node = Node::bind((listen, 0), options).await.unwrap();
+    let addr = node.local_addr().unwrap().to_string();
+    tokio::spawn(node.run());
+
+    // Dialed by an address outside the default names
+    let status = query_status(&addr, Some(&tls), None).await.unwrap();
+    assert_eq!(status.transaction_count, 0);
+    cleanup(tls);
+}
+
+#[tokio::test]
+async fn explains_a_plaintext_client_talking_to_a_tls_node() {
+    let tls = tls_options("plain-client");
+    let addr = start_node(Some(&tls)).await;
+
+    let error = query_status(&addr, None, None).await.err().unwrap();
+    assert!(
+        error.to_string().contains("speaking TLS"),
+        "unhelpful error: {}",
+        error
+    );
+    cleanup(tls);
+}
+
+#[tokio::test]
+async fn explains_a_tls_client_talking_to_a_plaintext_node() {
+    let tls = tls_options("tls-client");
+    node_tls(&tls);
+    let addr = start_node(None).await;
+
+    let error = query_status(&addr, Some(&tls), None).await.err().unwrap();
+    assert!(
+        error.to_string().contains("not speaking TLS"),
+        "unhelpful error: {}",
+        error
+    );
+    cleanup(tls);
+}
+
+#[tokio::test]
+async fn refuses_a_client_certificate_from_another_ca() {
+    let tls = tls_options("foreign-cert");
+    let addr = start_node(Some(&tls)).await;
+
+    // A CA the node has never heard of, and a client certificate it issued
+    let foreign = tls_options("foreign-ca");
+    node_tls(&foreign);
+    let issuer = Issuer::from_ca_cert_pem(
+        &fs::read_to_string(&foreign.ca).unwrap(),
+        KeyPair::from_pem(&fs::read_to_string(foreign.ca.with_extension("key")).unwrap()).unwrap(),
+    )
+    .unwrap();
+    let key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    let cert = params.signed_by(&key, &issuer).unwrap();
+    let dir = foreign.ca.parent().unwrap();
+    fs::write(dir.join("client.pem"), cert.pem()).unwrap();
+    fs::write(dir.join("client.key"), key.serialize_pem()).unwrap();
+
+    // Trusting the node's CA, so only the node can be the one refusing
+    let client = TlsOptions {
+        ca: tls.ca.clone(),
+        cert: Some(dir.join("client.pem")),
+        key: Some(dir.join("client.key")),
+    };
+    assert!(query_status(&addr, Some(&client), None).await.is_err());
+    cleanup(foreign);
+    cleanup(tls);
+}
+
+#[tokio::test]
+async fn refuses_a_tls_client_without_a_certificate() {
+    let tls = tls_options("no-cert");
+    let addr = start_node(Some(&tls)).await;
+
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(&tls.ca).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let socket = TcpStream::connect(&addr).await.unwrap();
+    let name = ServerName::try_from("127.0.0.1").unwrap();
+    // With TLS 1.3 the node may only object once the client has finished
+    let answered = match TlsConnector::from(Arc::new(config))
+        .connect(name, socket)
+        .await
+    {
+        Ok(stream) => answers_hello(stream).await,
+        Err(_) => false,
+    };
+    assert!(!answered, "a client without a certificate got a Hello");
+    cleanup(tls);
+}
+
+#[tokio::test]
+async fn peers_relay_transactions_over_tls() {
+    let tls = tls_options("peers");
+    let a = Node::bind("127.0.0.1:0", with_tls(Some(&tls), funded_options(100)))
+        .await
+        .unwrap();
+    let b = Node::bind("127.0.0.1:0", with_tls(Some(&tls), funded_options(100)))
+        .await
+        .unwrap();
+    let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+    let (a_state, b_state) = (a.state(), b.state());
+
+    b.connect(a_addr.to_string());
+    tokio::spawn(a.run());
+    tokio::spawn(b.run());
+    wait_until_connected(&a_state, 1).await;
+    wait_until_connected(&b_state, 1).await;
+
+    // Sent to B over TLS, and relayed to A over the TLS peer connection
+    let transaction = transfer(40, 1);
+    assert!(
+        send_transaction(&b_addr.to_string(), transaction.clone(), Some(&tls))
+            .await
+            .unwrap()
+    );
+    wait_for_transactions(&a_state, 1).await;
+    assert!(
+        a_state
+            .lock()
+            .await
+            .transaction(&transaction.signature)
+            .is_some()
+    );
+    cleanup(tls);
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use std::net::TcpListener;
+use std::path::Path;
+use std::time::Duration;
+
+use common::{funded_options, submit, transfer, wait_until_connected};
+use p2p_solana_network_simulation::topology::{Topology, TopologyNode};
+use tokio::time::{sleep, timeout};
+
+// The example file's ring, moved onto ports that are free right now
+fn ring_on_free_ports() -> Topology {
+    let example =
+        Topology::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("topology.yaml")).unwrap();
+    // All bound at once so no two are handed the same port, then released
+    let listeners: Vec<_> = example
+        .nodes
+        .iter()
+        .map(|_| TcpListener::bind("127.0.0.1:0").unwrap())
+        .collect();
+    let free: Vec<u16> = listeners
+        .iter()
+        .map(|listener| listener.local_addr().unwrap().port())
+        .collect();
+    drop(listeners);
+    let port_of = |port: u16| {
+        let index = example.nodes.iter().position(|n| n.port == port).unwrap();
+        free[index]
+    };
+    Topology {
+        nodes: example
+            .nodes
+            .iter()
+            .map(|node| TopologyNode {
+                port: port_of(node.port),
+                peers: node.peers.iter().map(|&peer| port_of(peer)).collect(),
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn example_ring_is_connected() {
+    let topology =
+        Topology::load(&Path::new(env!("CARGO_MANIFEST_DIR")).join("topology.yaml")).unwrap();
+    assert_eq!(topology.nodes.len(), 8);
+    assert!(topology.unreachable().is_empty());
+}
+
+#[tokio::test]
+async fn every_node_of_the_ring_hears_from_node_0() {
+    let topology = ring_on_free_ports();
+    let nodes = topology.start(&funded_options(100)).await.unwrap();
+    let states: Vec<_> = nodes.iter().map(|node| node.state()).collect();
+    for node in nodes {
+        tokio::spawn(node.run());
+    }
+
+    // Wait for the ring to close so the transaction takes both ways round
+    for state in &states {
+        wait_until_connected(state, 2).await;
+    }
+
+    let transaction = transfer(10, 1);
+    let addr = format!("127.0.0.1:{}", topology.nodes[0].port);
+    submit(&addr, [transaction.clone()]).await;
+
+    timeout(Duration::from_secs(10), async {
+        for state in &states {
+            while state
+                .lock()
+                .await
+                .transaction(&transaction.signature)
+                .is_none()
+            {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }
+    })
+    .await
+    .expect("the transaction never reached every node");
+}
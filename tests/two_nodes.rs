@@ -0,0 +1,117 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{funded_options, submit, transfer, wait_for_transactions, wait_until_connected};
+use p2p_solana_network_simulation::net::{Node, NodeOptions};
+use p2p_solana_network_simulation::protocol::{PeerInfo, Transaction, encode_pubkey};
+use tokio::time::{sleep, timeout};
+
+fn transaction() -> Transaction {
+    transfer(100, 1234567890)
+}
+
+// Both nodes start with the sender holding exactly one transaction's worth
+fn options() -> NodeOptions {
+    funded_options(100)
+}
+
+#[tokio::test]
+async fn transaction_propagates_between_two_nodes() {
+    let a = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    let b = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    let a_addr = a.local_addr().unwrap();
+    let a_id = a.node_id().to_string();
+    let (a_state, b_state) = (a.state(), b.state());
+
+    b.connect(a_addr.to_string());
+    tokio::spawn(a.run());
+    tokio::spawn(b.run());
+
+    // Wait for both ends of the handshake before injecting the transaction at A
+    wait_until_connected(&a_state, 1).await;
+    wait_until_connected(&b_state, 1).await;
+
+    submit(&a_addr.to_string(), [transaction()]).await;
+    wait_for_transactions(&b_state, 1).await;
+
+    assert_eq!(
+        a_state.lock().await.transactions_from(&transaction().from),
+        [&transaction()]
+    );
+    assert_eq!(
+        b_state.lock().await.transactions_from(&transaction().from),
+        [&transaction()]
+    );
+    let b_peers: Vec<_> = b_state.lock().await.peers().cloned().collect();
+    assert_eq!(
+        b_peers,
+        [PeerInfo {
+            node_id: a_id,
+            addr: a_addr.to_string(),
+            rtt_micros: None,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn conflicting_spends_apply_once_on_every_node() {
+    let a = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    let b = Node::bind("127.0.0.1:0", options()).await.unwrap();
+    let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+    let (a_state, b_state) = (a.state(), b.state());
+
+    b.connect(a_addr.to_string());
+    tokio::spawn(a.run());
+    tokio::spawn(b.run());
+    wait_until_connected(&a_state, 1).await;
+    wait_until_connected(&b_state, 1).await;
+
+    // Each spend uses the sender's whole balance. B applies the later one
+    // before the earlier one reaches A, and both must settle on the earlier.
+    let (first, second) = (transfer(100, 1), transfer(100, 2));
+    submit(&b_addr.to_string(), [second]).await;
+    wait_for_transactions(&b_state, 1).await;
+    submit(&a_addr.to_string(), [first.clone()]).await;
+
+    for state in [&a_state, &b_state] {
+        timeout(Duration::from_secs(5), async {
+            while state.lock().await.transaction(&first.signature).is_none() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the earlier spend never applied");
+    }
+    sleep(Duration::from_millis(200)).await;
+
+    let from = encode_pubkey(&common::sender().verifying_key());
+    for state in [a_state, b_state] {
+        let state = state.lock().await;
+        assert_eq!(state.transactions_from(&from), [&first]);
+        assert_eq!(state.balance(&from), 0);
+        assert_eq!(state.balance(&common::recipient()), 100);
+    }
+}
+
+#[tokio::test]
+async fn nodes_talk_over_ipv6() {
+    let a = Node::bind("[::1]:0", options()).await.unwrap();
+    let b = Node::bind("[::1]:0", options()).await.unwrap();
+    let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+    let (a_state, b_state) = (a.state(), b.state());
+
+    b.connect(a_addr.to_string());
+    tokio::spawn(a.run());
+    tokio::spawn(b.run());
+    wait_until_connected(&a_state, 1).await;
+    wait_until_connected(&b_state, 1).await;
+
+    submit(&a_addr.to_string(), [transaction()]).await;
+    wait_for_transactions(&b_state, 1).await;
+
+    // A learns B's address from the connection and the port in its Hello
+    let a_peers: Vec<_> = a_state.lock().await.peers().cloned().collect();
+    assert_eq!(a_peers.len(), 1);
+    assert_eq!(a_peers[0].addr, b_addr.to_string());
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use common::{funded_options, recipient, submit, transfer, wait_for_transactions};
+use p2p_solana_network_simulation::net::{Node, NodeOptions};
+use tokio::time::sleep;
+
+fn options(wal: &Path, compact_wal: bool) -> NodeOptions {
+    NodeOptions {
+        wal: Some(wal.to_path_buf()),
+        compact_wal,
+        ..funded_options(100)
+    }
+}
+
+#[tokio::test]
+async fn restarted_node_recovers_its_transactions() {
+    let wal = std::env::temp_dir().join(format!("p2p-node-wal-{}", std::process::id()));
+    let _ = fs::remove_file(&wal);
+
+    let node = Node::bind("127.0.0.1:0", options(&wal, false))
+        .await
+        .unwrap();
+    let addr = node.local_addr().unwrap();
+    let state = node.state();
+    tokio::spawn(node.run());
+
+    submit(
+        &addr.to_string(),
+        (0..20).map(|timestamp| transfer(5, timestamp)),
+    )
+    .await;
+    wait_for_transactions(&state, 20).await;
+    // Give the background sync a chance to flush the last partial batch
+    sleep(Duration::from_millis(300)).await;
+
+    // A torn write at the end of the log, as left by a crash
+    let mut contents = fs::read(&wal).unwrap();
+    contents.extend_from_slice(&[0, 0, 1, 0, 9]);
+    fs::write(&wal, contents).unwrap();
+
+    for compact in [false, true] {
+        let restarted = Node::bind("127.0.0.1:0", options(&wal, compact))
+            .await
+            .unwrap();
+        let restarted = restarted.state();
+        let restarted = restarted.lock().await;
+        let original = state.lock().await;
+        assert_eq!(restarted.transaction_count(), 20);
+        assert_eq!(restarted.ledger(), original.ledger());
+        assert_eq!(restarted.balance(&recipient()), 100);
+    }
+    fs::remove_file(&wal).unwrap();
+}